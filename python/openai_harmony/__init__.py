@@ -24,6 +24,8 @@ from typing import (
     Optional,
     Pattern,
     Sequence,
+    Set,
+    Tuple,
     TypeVar,
     Union,
 )
@@ -44,6 +46,12 @@ try:
     from .openai_harmony import (
         load_harmony_encoding as _load_harmony_encoding,  # type: ignore
     )
+    from .openai_harmony import (
+        get_tiktoken_base_url as _get_tiktoken_base_url,  # type: ignore
+    )
+    from .openai_harmony import (
+        set_tiktoken_base_url as _set_tiktoken_base_url,  # type: ignore
+    )
 
 except ModuleNotFoundError:  # pragma: no cover – raised during type-checking
     # When running *mypy* without the compiled extension in place we still want
@@ -59,6 +67,8 @@ except ModuleNotFoundError:  # pragma: no cover – raised during type-checking
             )
 
     _load_harmony_encoding = _Stub()  # type: ignore
+    _get_tiktoken_base_url = _Stub()  # type: ignore
+    _set_tiktoken_base_url = _Stub()  # type: ignore
     _PyHarmonyEncoding = _Stub()  # type: ignore
     _PyStreamableParser = _Stub()  # type: ignore
     _HarmonyError = RuntimeError
@@ -131,6 +141,7 @@ class ToolDescription(BaseModel):
     name: str
     description: str
     parameters: Optional[dict] = None
+    examples: List[Any] = Field(default_factory=list)
 
     @classmethod
     def new(
@@ -138,6 +149,10 @@ class ToolDescription(BaseModel):
     ) -> "ToolDescription":  # noqa: D401
         return cls(name=name, description=description, parameters=parameters)
 
+    def with_examples(self, examples: List[Any]) -> "ToolDescription":
+        self.examples = examples
+        return self
+
 
 class ReasoningEffort(str, Enum):
     LOW = "Low"
@@ -153,11 +168,28 @@ class ChannelConfig(BaseModel):
     def require_channels(cls, channels: List[str]) -> "ChannelConfig":  # noqa: D401
         return cls(valid_channels=channels, channel_required=True)
 
+    @classmethod
+    def analysis_and_final(cls) -> "ChannelConfig":
+        return cls.require_channels(["analysis", "final"])
+
+    @classmethod
+    def analysis_commentary_final(cls) -> "ChannelConfig":
+        return cls.require_channels(["analysis", "commentary", "final"])
+
+    @classmethod
+    def single_channel(cls, channel: str) -> "ChannelConfig":
+        return cls.require_channels([channel])
+
 
 class ToolNamespaceConfig(BaseModel):
     name: str
     description: Optional[str] = None
     tools: List[ToolDescription]
+    rate_limit: Optional[str] = None
+
+    def with_rate_limit(self, description: str) -> "ToolNamespaceConfig":
+        self.rate_limit = description
+        return self
 
     @staticmethod
     def browser() -> "ToolNamespaceConfig":
@@ -177,6 +209,46 @@ class ToolNamespaceConfig(BaseModel):
         cfg = _get_tool_namespace_config("python")
         return ToolNamespaceConfig(**cfg)
 
+    @staticmethod
+    def code_interpreter() -> "ToolNamespaceConfig":
+        from .openai_harmony import (
+            get_tool_namespace_config as _get_tool_namespace_config,
+        )
+
+        cfg = _get_tool_namespace_config("code_interpreter")
+        return ToolNamespaceConfig(**cfg)
+
+    @staticmethod
+    def dalle() -> "ToolNamespaceConfig":
+        from .openai_harmony import (
+            get_tool_namespace_config as _get_tool_namespace_config,
+        )
+
+        cfg = _get_tool_namespace_config("dalle")
+        return ToolNamespaceConfig(**cfg)
+
+    @staticmethod
+    def web_search() -> "ToolNamespaceConfig":
+        from .openai_harmony import (
+            get_tool_namespace_config as _get_tool_namespace_config,
+        )
+
+        cfg = _get_tool_namespace_config("web_search")
+        return ToolNamespaceConfig(**cfg)
+
+    def validate_tool_call(self, tool_name: str, args_json: str) -> None:
+        """Validate *args_json* against the parameter schema of *tool_name*.
+
+        Raises ``HarmonyError`` if the namespace has no tool called *tool_name*
+        or the arguments do not match the schema.
+        """
+        from .openai_harmony import validate_tool_call as _validate_tool_call
+
+        tool = next((t for t in self.tools if t.name == tool_name), None)
+        if tool is None:
+            raise HarmonyError(f"Unknown tool {tool_name!r} in namespace {self.name!r}")
+        _validate_tool_call(tool.model_dump_json(), args_json)
+
 
 class SystemContent(Content):
     model_identity: Optional[str] = (
@@ -191,6 +263,9 @@ class SystemContent(Content):
         )
     )
     tools: Optional[dict[str, ToolNamespaceConfig]] = None
+    response_format: Optional[dict] = None
+    content_type: Optional[str] = None
+    custom_sections: List[Tuple[str, str]] = Field(default_factory=list)
 
     @classmethod
     def new(cls) -> "SystemContent":
@@ -232,6 +307,17 @@ class SystemContent(Content):
         self.tools[ns_config.name] = ns_config
         return self
 
+    def with_json_response_format(
+        self, schema: Optional[dict] = None
+    ) -> "SystemContent":
+        self.response_format = schema
+        self.content_type = "<|constrain|>json"
+        return self
+
+    def with_custom_section(self, title: str, body: str) -> "SystemContent":
+        self.custom_sections.append((title, body))
+        return self
+
     def with_browser_tool(self) -> "SystemContent":
         return self.with_tools(ToolNamespaceConfig.browser())
 
@@ -273,6 +359,12 @@ class DeveloperContent(Content):
             ToolNamespaceConfig(name="functions", description=None, tools=list(tools))
         )
 
+    def with_browser_tool(self) -> "DeveloperContent":
+        return self.with_tools(ToolNamespaceConfig.browser())
+
+    def with_code_interpreter_tool(self) -> "DeveloperContent":
+        return self.with_tools(ToolNamespaceConfig.python())
+
     def to_dict(self) -> dict:
         out = self.model_dump(exclude_none=True)
         out["type"] = "developer_content"
@@ -292,6 +384,7 @@ class Message(BaseModel):
     channel: Optional[str] = None
     recipient: Optional[str] = None
     content_type: Optional[str] = None
+    id: Optional[str] = None
 
     # ------------------------------------------------------------------
     # Convenience constructors (mirroring the Rust API)
@@ -339,6 +432,10 @@ class Message(BaseModel):
         self.content_type = content_type
         return self
 
+    def with_id(self, id: str) -> "Message":
+        self.id = id
+        return self
+
     # ------------------------------------------------------------------
     # Serialisation helpers
     # ------------------------------------------------------------------
@@ -354,6 +451,8 @@ class Message(BaseModel):
             out["recipient"] = self.recipient
         if self.content_type is not None:
             out["content_type"] = self.content_type
+        if self.id is not None:
+            out["id"] = self.id
         return out
 
     def to_json(self) -> str:  # noqa: D401
@@ -389,6 +488,7 @@ class Message(BaseModel):
         msg.channel = data.get("channel")
         msg.recipient = data.get("recipient")
         msg.content_type = data.get("content_type")
+        msg.id = data.get("id")
         return msg
 
 
@@ -402,6 +502,9 @@ class Conversation(BaseModel):
     def __iter__(self):
         return iter(self.messages)
 
+    def find_message_by_id(self, id: str) -> Optional[Message]:
+        return next((m for m in self.messages if m.id == id), None)
+
     # Serialisation helpers -------------------------------------------------
 
     def to_dict(self) -> Dict[str, Any]:  # noqa: D401
@@ -443,6 +546,11 @@ class HarmonyEncoding:
     def name(self) -> str:  # noqa: D401
         return self._inner.name  # type: ignore[attr-defined]
 
+    @property
+    def pattern(self) -> str:
+        """Regex pattern the tokenizer uses to split text before applying BPE."""
+        return self._inner.pattern  # type: ignore[attr-defined]
+
     @functools.cached_property
     def special_tokens_set(self) -> set[str]:
         return set(self._inner.special_tokens())
@@ -472,6 +580,27 @@ class HarmonyEncoding:
             config=config_dict,
         )
 
+    def render_conversation_for_completion_batch(
+        self,
+        conversations: List[Conversation],
+        next_turn_role: Role,
+        config: Optional[RenderConversationConfig] = None,
+    ) -> List[List[int]]:
+        """Render many conversations for completion in parallel.
+
+        Equivalent to calling :meth:`render_conversation_for_completion` for
+        each conversation, but releases the GIL and renders on all cores.
+        """
+        if config is None:
+            config_dict = {"auto_drop_analysis": True}
+        else:
+            config_dict = {"auto_drop_analysis": config.auto_drop_analysis}
+        return self._inner.render_conversation_for_completion_batch(
+            conversations_json=[c.to_json() for c in conversations],
+            next_turn_role=str(next_turn_role.value),
+            config=config_dict,
+        )
+
     def render_conversation(
         self,
         conversation: Conversation,
@@ -502,6 +631,38 @@ class HarmonyEncoding:
             config=config_dict,
         )
 
+    def count_tokens(
+        self,
+        conversation: Conversation,
+        next_turn_role: Role,
+        config: Optional[RenderConversationConfig] = None,
+    ) -> int:
+        """Number of tokens `render_conversation_for_completion` would produce."""
+        if config is None:
+            config_dict = {"auto_drop_analysis": True}
+        else:
+            config_dict = {"auto_drop_analysis": config.auto_drop_analysis}
+        return self._inner.count_tokens(
+            conversation_json=conversation.to_json(),
+            next_turn_role=str(next_turn_role.value),
+            config=config_dict,
+        )
+
+    def count_conversation_tokens(
+        self,
+        conversation: Conversation,
+        config: Optional[RenderConversationConfig] = None,
+    ) -> int:
+        """Number of tokens `render_conversation` would produce."""
+        if config is None:
+            config_dict = {"auto_drop_analysis": True}
+        else:
+            config_dict = {"auto_drop_analysis": config.auto_drop_analysis}
+        return self._inner.count_conversation_tokens(
+            conversation_json=conversation.to_json(),
+            config=config_dict,
+        )
+
     def render(
         self, message: Message, render_options: Optional[RenderOptions] = None
     ) -> List[int]:
@@ -533,6 +694,10 @@ class HarmonyEncoding:
         """Decode a list of tokens into a UTF-8 string. Will raise an error if the tokens result in invalid UTF-8. Use decode if you want to replace invalid UTF-8 with the unicode replacement character."""
         return self._inner.decode_utf8(list(tokens))
 
+    def decode_utf8_lossy(self, tokens: Sequence[int]) -> str:
+        """Decode a list of tokens into a string, replacing invalid UTF-8 (e.g. a multi-byte character cut off mid-way) with the unicode replacement character instead of raising."""
+        return self._inner.decode_utf8_lossy(list(tokens))
+
     def encode(
         self,
         text: str,
@@ -583,6 +748,19 @@ class HarmonyEncoding:
             text = text.encode("utf-16", "surrogatepass").decode("utf-16", "replace")
             return self._inner.encode(text, list(allowed_special))
 
+    def encode_with_offsets(
+        self,
+        text: str,
+        allowed_special: Sequence[str] = (),
+    ) -> tuple[list[int], list[tuple[int, int]]]:
+        """Encodes a string into tokens, also returning the ``(start_byte, end_byte)``
+        range of the UTF-8 encoded text that each token covers.
+
+        Text matching a special token is only encoded as that special token if it
+        is listed in `allowed_special`; otherwise it is encoded as natural text.
+        """
+        return self._inner.encode_with_offsets(text, list(allowed_special))
+
     def decode(self, tokens: Sequence[int], errors: str = "replace") -> str:
         """Decodes a list of tokens into a string.
 
@@ -609,6 +787,12 @@ class HarmonyEncoding:
     def stop_tokens_for_assistant_actions(self) -> List[int]:
         return self._inner.stop_tokens_for_assistant_actions()
 
+    def all_stop_tokens(self) -> Set[int]:
+        return self._inner.all_stop_tokens()
+
+    def is_stop_token(self, token: int) -> bool:
+        return self._inner.is_stop_token(token)
+
 
 class StreamState(Enum):
     EXPECT_START = "ExpectStart"
@@ -631,6 +815,20 @@ class StreamableParser:
         self._inner.process_eos()
         return self
 
+    def process_str(
+        self, text: str, allowed_special: Sequence[str] | None = None
+    ) -> "StreamableParser":
+        """Encode ``text`` with the parser's encoding and process every resulting
+        token, e.g. when the inference engine returns text instead of tokens.
+
+        All special tokens in ``text`` are recognised unless ``allowed_special``
+        restricts them; any other special-token text is parsed as plain text.
+        """
+        self._inner.process_str(
+            text, None if allowed_special is None else list(allowed_special)
+        )
+        return self
+
     @property
     def current_content(self) -> str:
         return self._inner.current_content
@@ -690,6 +888,22 @@ def load_harmony_encoding(name: str | "HarmonyEncodingName") -> HarmonyEncoding:
     return HarmonyEncoding(inner)
 
 
+def set_tiktoken_base_url(url: str) -> None:
+    """Override the base URL vocab files are downloaded from.
+
+    Must be called before the first encoding is loaded and can only be called
+    once; later calls raise :class:`HarmonyError`.
+    """
+
+    _set_tiktoken_base_url(url)
+
+
+def get_tiktoken_base_url() -> str:
+    """Return the base URL vocab files are downloaded from."""
+
+    return _get_tiktoken_base_url()
+
+
 # For *mypy* we expose a minimal stub of the `HarmonyEncodingName` enum.  At
 # **runtime** the user is expected to pass the *string* names because the Rust
 # side only operates on strings anyway.
@@ -716,6 +930,8 @@ __all__ = [
     "HarmonyEncoding",
     "HarmonyEncodingName",
     "load_harmony_encoding",
+    "set_tiktoken_base_url",
+    "get_tiktoken_base_url",
     "StreamableParser",
     "StreamState",
     "HarmonyError",
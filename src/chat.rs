@@ -1,10 +1,15 @@
+use crate::{
+    encoding::{FormattingToken, HarmonyEncoding, RenderConversationConfig},
+    tiktoken::Rank,
+};
+use anyhow::Context as _;
 use core::fmt;
 use tsify::Tsify;
 use serde::{
     de::{self, Visitor},
     Deserialize, Deserializer, Serialize,
 };
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::{fmt::Display, marker::PhantomData};
 
 #[serde_with::skip_serializing_none]
@@ -29,7 +34,7 @@ impl From<Role> for Author {
     }
 }
 
-#[derive(Tsify, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[derive(Tsify, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 #[serde(rename_all = "snake_case")]
 pub enum Role {
     User,
@@ -63,6 +68,12 @@ impl Role {
             Role::Tool => "tool",
         }
     }
+
+    /// Whether messages from this role carry privileged instructions, i.e.
+    /// come from the system or developer.
+    pub fn is_privileged(&self) -> bool {
+        matches!(self, Role::System | Role::Developer)
+    }
 }
 
 impl Display for Role {
@@ -71,6 +82,8 @@ impl Display for Role {
     }
 }
 
+// Boxing `SystemContent` would break matching on `Content` for downstream users.
+#[allow(clippy::large_enum_variant)]
 #[derive(Tsify, Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum Content {
@@ -102,6 +115,56 @@ impl From<DeveloperContent> for Content {
     }
 }
 
+/// Typed view of [`Message::content_type`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ContentType {
+    /// No content type, i.e. plain text.
+    Plain,
+    /// `code`
+    Code,
+    /// `<|constrain|>json`
+    ConstrainedJson,
+    /// Any other content type, kept verbatim.
+    Custom(String),
+}
+
+impl ContentType {
+    /// The string stored in [`Message::content_type`]; empty for
+    /// [`ContentType::Plain`].
+    pub fn as_str(&self) -> &str {
+        match self {
+            ContentType::Plain => "",
+            ContentType::Code => "code",
+            ContentType::ConstrainedJson => "<|constrain|>json",
+            ContentType::Custom(s) => s,
+        }
+    }
+}
+
+impl From<&str> for ContentType {
+    fn from(value: &str) -> Self {
+        match value {
+            "" => ContentType::Plain,
+            "code" => ContentType::Code,
+            "<|constrain|>json" => ContentType::ConstrainedJson,
+            other => ContentType::Custom(other.to_string()),
+        }
+    }
+}
+
+impl std::str::FromStr for ContentType {
+    type Err = std::convert::Infallible;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.into())
+    }
+}
+
+impl Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 #[serde_with::skip_serializing_none]
 #[derive(Tsify, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Message {
@@ -136,6 +199,11 @@ pub struct Message {
 
     /// Content type of the message. This is typically only set by the model, you probably don't need to set this.
     pub content_type: Option<String>,
+
+    /// Caller supplied identifier of the message, e.g. to refer to it across
+    /// conversation edits. It is never rendered.
+    #[serde(default)]
+    pub id: Option<String>,
 }
 
 impl Message {
@@ -149,6 +217,7 @@ impl Message {
             channel: None,
             recipient: None,
             content_type: None,
+            id: None,
         }
     }
 
@@ -169,6 +238,7 @@ impl Message {
             channel: None,
             recipient: None,
             content_type: None,
+            id: None,
         }
     }
     pub fn adding_content<C>(mut self, content: C) -> Self
@@ -199,6 +269,156 @@ impl Message {
         self.content_type = Some(content_type.into());
         self
     }
+    /// The content type as a [`ContentType`]; [`ContentType::Plain`] if unset.
+    pub fn typed_content_type(&self) -> ContentType {
+        self.content_type
+            .as_deref()
+            .map_or(ContentType::Plain, ContentType::from)
+    }
+    /// Sets the content type from a [`ContentType`], clearing it for `Plain`.
+    pub fn with_typed_content_type(mut self, content_type: ContentType) -> Self {
+        self.content_type = match content_type {
+            ContentType::Plain => None,
+            other => Some(other.as_str().to_string()),
+        };
+        self
+    }
+    /// Whether the content type is constrained JSON (`<|constrain|>json`).
+    pub fn content_type_is_json(&self) -> bool {
+        self.typed_content_type() == ContentType::ConstrainedJson
+    }
+
+    /// Whether the content type is code (`code`).
+    pub fn content_type_is_code(&self) -> bool {
+        self.typed_content_type() == ContentType::Code
+    }
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Parse a message from its JSON representation.
+    pub fn from_json(s: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(s)
+    }
+
+    /// Parse a message from an already parsed JSON value.
+    pub fn from_json_value(v: serde_json::Value) -> serde_json::Result<Self> {
+        serde_json::from_value(v)
+    }
+
+    /// Serialize the message to compact JSON, the inverse of
+    /// [`Self::from_json`].
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    /// The author as it appears in the rendered header, e.g. `assistant`,
+    /// `user:alice` or, for tools, just the tool name such as
+    /// `functions.get_weather`.
+    pub fn author_display(&self) -> String {
+        match (&self.author.role, &self.author.name) {
+            (Role::Tool, Some(name)) => name.clone(),
+            (role, Some(name)) => format!("{role}:{name}"),
+            (role, None) => role.to_string(),
+        }
+    }
+
+    /// Whether this is an assistant message addressed to a tool.
+    pub fn is_tool_call(&self) -> bool {
+        self.author.role == Role::Assistant && self.recipient.as_deref().is_some_and(|r| r != "all")
+    }
+
+    /// Whether this is a message from a tool.
+    pub fn is_tool_response(&self) -> bool {
+        self.author.role == Role::Tool
+    }
+
+    /// Append `text` to the single text content of this message instead of
+    /// pushing a new `Content` entry, e.g. when assembling streamed output.
+    ///
+    /// Fails if the message doesn't have exactly one text content.
+    pub fn with_text_appended(mut self, text: &str) -> anyhow::Result<Self> {
+        let mut texts = self.content.iter_mut().filter_map(|c| match c {
+            Content::Text(TextContent { text }) => Some(text),
+            _ => None,
+        });
+        match (texts.next(), texts.next()) {
+            (Some(existing), None) => existing.push_str(text),
+            (None, _) => anyhow::bail!("message has no text content to append to"),
+            (Some(_), Some(_)) => anyhow::bail!("message has multiple text contents"),
+        }
+        Ok(self)
+    }
+
+    /// Set the content type to `<|constrain|>json` if the text content of this
+    /// message is valid JSON. Leaves the content type unchanged otherwise.
+    pub fn set_content_type_from_content(mut self) -> Self {
+        let text: String = self
+            .content
+            .iter()
+            .filter_map(|c| match c {
+                Content::Text(TextContent { text }) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        if let Some(content_type) = HarmonyEncoding::infer_content_type_from_json(&text) {
+            self.content_type = Some(content_type);
+        }
+        self
+    }
+
+    /// Truncate the text content of this message so that it fits within
+    /// `max_tokens` tokens when encoded with `encode_ordinary`.
+    ///
+    /// Text blocks are consumed from the front, so the last text block is the
+    /// first one to be shortened or dropped. Non-text content is left as is.
+    pub fn truncate_content_to_tokens(
+        mut self,
+        encoding: &HarmonyEncoding,
+        max_tokens: usize,
+    ) -> anyhow::Result<Self> {
+        let tokenizer = encoding.tokenizer();
+        let mut remaining = max_tokens;
+        let mut content = Vec::with_capacity(self.content.len());
+        for item in self.content {
+            let Content::Text(TextContent { text }) = item else {
+                content.push(item);
+                continue;
+            };
+            let tokens = tokenizer.encode_ordinary(&text);
+            if tokens.len() <= remaining {
+                remaining -= tokens.len();
+                content.push(Content::Text(TextContent { text }));
+                continue;
+            }
+            if remaining == 0 {
+                continue;
+            }
+            // Decode the tokens that fit and cut back to a char boundary. Since
+            // re-encoding the prefix may merge differently, keep shrinking until
+            // it actually fits.
+            let bytes = tokenizer.decode_bytes(&tokens[..remaining])?;
+            let valid_len = match std::str::from_utf8(&bytes) {
+                Ok(s) => s.len(),
+                Err(e) => e.valid_up_to(),
+            };
+            let mut prefix = &text[..valid_len];
+            while tokenizer.encode_ordinary(prefix).len() > remaining {
+                let mut chars = prefix.chars();
+                chars.next_back();
+                prefix = chars.as_str();
+            }
+            if !prefix.is_empty() {
+                content.push(Content::Text(TextContent {
+                    text: prefix.to_string(),
+                }));
+            }
+            remaining = 0;
+        }
+        self.content = content;
+        Ok(self)
+    }
 }
 
 #[derive(Tsify, Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -235,6 +455,25 @@ impl ChannelConfig {
             channel_required: true,
         }
     }
+
+    /// `analysis` and `final`, for models that reason before answering but
+    /// don't call any tools.
+    pub fn analysis_and_final() -> Self {
+        Self::require_channels(["analysis", "final"])
+    }
+
+    /// `analysis`, `commentary` and `final`, the default for system messages.
+    /// Use this whenever function tools are available, since calls to them go
+    /// to the `commentary` channel.
+    pub fn analysis_commentary_final() -> Self {
+        Self::require_channels(["analysis", "commentary", "final"])
+    }
+
+    /// A single required channel, e.g. `final` to have the model answer
+    /// directly without a separate reasoning channel.
+    pub fn single_channel(channel: impl Into<String>) -> Self {
+        Self::require_channels([channel.into()])
+    }
 }
 
 #[derive(Tsify, Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -242,6 +481,10 @@ pub struct ToolNamespaceConfig {
     pub name: String,
     pub description: Option<String>,
     pub tools: Vec<ToolDescription>,
+    /// Rate limit rendered as a `// Rate limit: ...` comment after the
+    /// namespace description, e.g. `10 calls per minute`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<String>,
 }
 
 impl ToolNamespaceConfig {
@@ -254,9 +497,73 @@ impl ToolNamespaceConfig {
             name: name.into(),
             description,
             tools,
+            rate_limit: None,
         }
     }
 
+    pub fn with_rate_limit(mut self, description: impl Into<String>) -> Self {
+        self.rate_limit = Some(description.into());
+        self
+    }
+
+    /// Convert the tools of this namespace to the OpenAI function calling
+    /// format, i.e. `[{"type": "function", "function": {"name", "description",
+    /// "parameters"}}]`. Tool examples are not part of that format and are
+    /// dropped.
+    pub fn to_openai_tools_json(&self) -> anyhow::Result<String> {
+        let tools: Vec<serde_json::Value> = self
+            .tools
+            .iter()
+            .map(|tool| {
+                let mut function = serde_json::json!({
+                    "name": tool.name,
+                    "description": tool.description,
+                });
+                if let Some(parameters) = &tool.parameters {
+                    function["parameters"] = parameters.clone();
+                }
+                serde_json::json!({"type": "function", "function": function})
+            })
+            .collect();
+        Ok(serde_json::to_string(&tools)?)
+    }
+
+    /// Create a namespace from tools in the OpenAI function calling format,
+    /// see [`Self::to_openai_tools_json`].
+    pub fn from_openai_tools_json(
+        json: &str,
+        namespace_name: impl Into<String>,
+    ) -> anyhow::Result<Self> {
+        let tools: Vec<serde_json::Value> =
+            serde_json::from_str(json).context("expected a JSON array of tools")?;
+        let tools = tools
+            .iter()
+            .enumerate()
+            .map(|(idx, tool)| {
+                if tool.get("type").and_then(|t| t.as_str()) != Some("function") {
+                    anyhow::bail!("tool {idx} is not of type \"function\"");
+                }
+                let function = tool
+                    .get("function")
+                    .with_context(|| format!("tool {idx} has no \"function\" object"))?;
+                let name = function
+                    .get("name")
+                    .and_then(|n| n.as_str())
+                    .with_context(|| format!("tool {idx} has no name"))?;
+                let description = function
+                    .get("description")
+                    .and_then(|d| d.as_str())
+                    .unwrap_or_default();
+                Ok(ToolDescription::new(
+                    name,
+                    description,
+                    function.get("parameters").cloned(),
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self::new(namespace_name, None, tools))
+    }
+
     pub fn browser() -> Self {
         ToolNamespaceConfig::new(
             "browser",
@@ -316,6 +623,56 @@ impl ToolNamespaceConfig {
             vec![],
         )
     }
+
+    pub fn code_interpreter() -> Self {
+        ToolNamespaceConfig::new(
+            "code_interpreter",
+            Some("Use this tool to run code in a sandboxed, stateful environment. Send the code as the message to code_interpreter; it will respond with the output of the execution, including any errors, or time out after 120.0 seconds. Files saved to '/mnt/data' persist for the rest of the session.".to_string()),
+            vec![],
+        )
+    }
+
+    pub fn dalle() -> Self {
+        ToolNamespaceConfig::new(
+            "dalle",
+            Some("Use this tool to generate images from a text description. Write a detailed prompt in English describing the image, and do not ask the user for permission before generating it. The generated images are shown to the user directly, so do not describe or link them in your response.".to_string()),
+            vec![ToolDescription::new(
+                "text2im",
+                "Generates images from `prompt`.",
+                Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "prompt": {"type": "string"},
+                        "size": {
+                            "type": "string",
+                            "enum": ["1024x1024", "1792x1024", "1024x1792"],
+                            "default": "1024x1024"
+                        },
+                        "n": {"type": "number", "default": 1}
+                    },
+                    "required": ["prompt"]
+                })),
+            )],
+        )
+    }
+
+    pub fn web_search() -> Self {
+        ToolNamespaceConfig::new(
+            "web_search",
+            Some("Tool for searching the web. Use it for questions about current events or information that is likely to have changed since the knowledge cutoff, and cite the sources the answer is based on.".to_string()),
+            vec![ToolDescription::new(
+                "search",
+                "Searches the web for `query` and returns the top results.",
+                Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "query": {"type": "string"}
+                    },
+                    "required": ["query"]
+                })),
+            )],
+        )
+    }
 }
 
 /// Content specific to system messages, includes model identity and its instructions
@@ -333,6 +690,18 @@ pub struct SystemContent {
 
     /// Channel configuration for the system message.
     pub channel_config: Option<ChannelConfig>,
+
+    /// JSON schema the model's response should conform to, rendered as a
+    /// TypeScript type in the `# Response format` section.
+    pub response_format: Option<serde_json::Value>,
+
+    /// Content type the model is expected to respond with, e.g.
+    /// `<|constrain|>json` when a JSON response format is requested.
+    pub content_type: Option<String>,
+
+    /// Additional `(title, body)` sections rendered after the standard ones.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub custom_sections: Vec<(String, String)>,
 }
 
 impl Default for SystemContent {
@@ -345,11 +714,10 @@ impl Default for SystemContent {
             tools: None,
             conversation_start_date: None,
             knowledge_cutoff: Some("2024-06".to_string()),
-            channel_config: Some(ChannelConfig::require_channels([
-                "analysis",
-                "commentary",
-                "final",
-            ])),
+            channel_config: Some(ChannelConfig::analysis_commentary_final()),
+            response_format: None,
+            content_type: None,
+            custom_sections: Vec::new(),
         }
     }
 }
@@ -358,6 +726,55 @@ impl SystemContent {
     pub fn new() -> Self {
         Default::default()
     }
+    /// System content for a plain system prompt string, e.g. from an
+    /// OpenAI-style chat request: `text` becomes an `# Instructions` custom
+    /// section (unless it is blank) and no model identity, reasoning effort or
+    /// tools are set.
+    pub fn from_plain_text(text: impl Into<String>) -> Self {
+        let text = text.into();
+        let content = Self {
+            model_identity: None,
+            reasoning_effort: None,
+            tools: None,
+            ..Self::default()
+        };
+        if text.trim().is_empty() {
+            return content;
+        }
+        content.with_custom_section("Instructions", text)
+    }
+
+    /// Like [`Self::from_plain_text`], but first pulls well-known
+    /// `Knowledge cutoff: YYYY-MM` and `Current date: ...` lines out of
+    /// `text` into [`Self::knowledge_cutoff`] and
+    /// [`Self::conversation_start_date`].
+    pub fn from_openai_system_prompt(text: impl Into<String>) -> Self {
+        let text = text.into();
+        let mut knowledge_cutoff = None;
+        let mut conversation_start_date = None;
+        let mut remaining = Vec::new();
+        for line in text.lines() {
+            let field = line
+                .split_once(':')
+                .map(|(key, value)| (key.trim().to_ascii_lowercase(), value.trim()));
+            match field {
+                Some((key, value)) if key == "knowledge cutoff" && !value.is_empty() => {
+                    knowledge_cutoff = Some(value.to_string());
+                }
+                Some((key, value)) if key == "current date" && !value.is_empty() => {
+                    conversation_start_date = Some(value.to_string());
+                }
+                _ => remaining.push(line),
+            }
+        }
+        let mut content = Self::from_plain_text(remaining.join("\n").trim());
+        if knowledge_cutoff.is_some() {
+            content.knowledge_cutoff = knowledge_cutoff;
+        }
+        content.conversation_start_date = conversation_start_date;
+        content
+    }
+
     pub fn with_model_identity(mut self, model_identity: impl Into<String>) -> Self {
         self.model_identity = Some(model_identity.into());
         self
@@ -401,6 +818,86 @@ impl SystemContent {
         self
     }
 
+    /// Instruct the model to respond in JSON, optionally conforming to `schema`.
+    pub fn with_json_response_format(mut self, schema: Option<serde_json::Value>) -> Self {
+        self.response_format = schema;
+        self.content_type = Some(ContentType::ConstrainedJson.to_string());
+        self
+    }
+
+    /// Add a `# {title}` section with `body` to the end of the system message,
+    /// e.g. for persona descriptions or business rules.
+    pub fn with_custom_section(
+        mut self,
+        title: impl Into<String>,
+        body: impl Into<String>,
+    ) -> Self {
+        self.custom_sections.push((title.into(), body.into()));
+        self
+    }
+
+    /// Merge `other` into `self`, with `other` taking precedence: its
+    /// settings replace ours where set, its tool namespaces replace ours of
+    /// the same name and its custom sections are appended. Distinct model
+    /// identities are joined with a newline.
+    ///
+    /// Settings of `other` equal to [`SystemContent::default`]'s only fill in
+    /// unset ones, so overrides built with [`SystemContent::new`] keep ours.
+    fn merge_from(&mut self, other: SystemContent) {
+        fn merge_setting<T: PartialEq>(
+            ours: &mut Option<T>,
+            theirs: Option<T>,
+            default: Option<T>,
+        ) {
+            if let Some(theirs) = theirs.filter(|t| ours.is_none() || default.as_ref() != Some(t)) {
+                *ours = Some(theirs);
+            }
+        }
+
+        let defaults = SystemContent::default();
+        let their_identity = other.model_identity.filter(|theirs| {
+            self.model_identity.is_none() || defaults.model_identity.as_ref() != Some(theirs)
+        });
+        self.model_identity = match (self.model_identity.take(), their_identity) {
+            (Some(ours), Some(theirs)) if ours != theirs => Some(format!("{ours}\n{theirs}")),
+            (ours, theirs) => theirs.or(ours),
+        };
+        if let Some(tools) = other.tools {
+            self.tools.get_or_insert_with(BTreeMap::new).extend(tools);
+        }
+        merge_setting(
+            &mut self.reasoning_effort,
+            other.reasoning_effort,
+            defaults.reasoning_effort,
+        );
+        merge_setting(
+            &mut self.conversation_start_date,
+            other.conversation_start_date,
+            defaults.conversation_start_date,
+        );
+        merge_setting(
+            &mut self.knowledge_cutoff,
+            other.knowledge_cutoff,
+            defaults.knowledge_cutoff,
+        );
+        merge_setting(
+            &mut self.channel_config,
+            other.channel_config,
+            defaults.channel_config,
+        );
+        merge_setting(
+            &mut self.response_format,
+            other.response_format,
+            defaults.response_format,
+        );
+        merge_setting(
+            &mut self.content_type,
+            other.content_type,
+            defaults.content_type,
+        );
+        self.custom_sections.extend(other.custom_sections);
+    }
+
     pub fn with_browser_tool(mut self) -> Self {
         self = self.with_tools(ToolNamespaceConfig::browser());
         self
@@ -417,6 +914,11 @@ pub struct ToolDescription {
     pub name: String,
     pub description: String,
     pub parameters: Option<serde_json::Value>,
+    /// Few-shot examples rendered as comments after the tool signature. An
+    /// example is either the call arguments, or an object with `input`
+    /// arguments and an optional `output`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<serde_json::Value>,
 }
 
 impl ToolDescription {
@@ -429,8 +931,111 @@ impl ToolDescription {
             name: name.into(),
             description: description.into(),
             parameters,
+            examples: Vec::new(),
         }
     }
+
+    pub fn with_examples(mut self, examples: Vec<serde_json::Value>) -> Self {
+        self.examples = examples;
+        self
+    }
+
+    /// Validate the arguments of a call to this tool against its `parameters`
+    /// schema. Tools without a parameter schema accept any arguments.
+    ///
+    /// On failure the returned error wraps a [`ToolCallValidationError`].
+    #[cfg(feature = "jsonschema")]
+    pub fn validate_against_schema(&self, call_json: &serde_json::Value) -> anyhow::Result<()> {
+        let Some(schema) = &self.parameters else {
+            return Ok(());
+        };
+        let validator = jsonschema::validator_for(schema).map_err(|e| {
+            ToolCallValidationError::InvalidSchema {
+                tool: self.name.clone(),
+                message: e.to_string(),
+            }
+        })?;
+        let errors: Vec<String> = validator
+            .iter_errors(call_json)
+            .map(|e| match e.instance_path.to_string() {
+                path if path.is_empty() => e.to_string(),
+                path => format!("{path}: {e}"),
+            })
+            .collect();
+        if !errors.is_empty() {
+            return Err(ToolCallValidationError::SchemaViolation {
+                tool: self.name.clone(),
+                errors,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Error returned by [`ToolDescription::validate_against_schema`].
+#[derive(Debug, thiserror::Error)]
+pub enum ToolCallValidationError {
+    #[error("invalid parameter schema for tool {tool}: {message}")]
+    InvalidSchema { tool: String, message: String },
+
+    #[error("arguments for tool {tool} do not match its schema: {}", errors.join("; "))]
+    SchemaViolation { tool: String, errors: Vec<String> },
+}
+
+/// Error returned when building a [`Conversation`] from inconsistent
+/// messages, see [`Conversation::append_tool_exchange`] and
+/// [`Conversation::validate`].
+#[derive(Debug, thiserror::Error)]
+pub enum ConversationBuildError {
+    #[error("expected an assistant message addressed to a tool")]
+    NotAToolCall,
+
+    #[error("expected a message from a tool")]
+    NotAToolResponse,
+
+    #[error("tool call to {recipient} answered by {}", responder.as_deref().unwrap_or("unnamed tool"))]
+    MismatchedTool {
+        recipient: String,
+        responder: Option<String>,
+    },
+
+    #[error("system message at index {index} is not the first message")]
+    SystemNotFirst { index: usize },
+
+    #[error("index {index} is out of bounds for a conversation of {len} messages")]
+    IndexOutOfBounds { index: usize, len: usize },
+}
+
+/// A problem with a single message found by
+/// [`Conversation::validation_errors`].
+///
+/// Each variant carries the index of the offending message and enough
+/// context to describe the problem without the conversation at hand.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ConversationValidationError {
+    #[error("message {message_index}: {role} messages cannot have content type {content_type:?}")]
+    WrongContentTypeForRole {
+        message_index: usize,
+        role: Role,
+        content_type: String,
+    },
+
+    #[error("message {message_index}: tool message has no tool name")]
+    MissingToolName { message_index: usize },
+
+    #[error(
+        "message {message_index}: channel {channel:?} is not one of the configured channels ({})",
+        valid_channels.join(", ")
+    )]
+    ChannelNotInConfig {
+        message_index: usize,
+        channel: String,
+        valid_channels: Vec<String>,
+    },
+
+    #[error("message {message_index}: message has no content")]
+    EmptyContent { message_index: usize },
 }
 
 #[derive(Tsify, Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -447,6 +1052,626 @@ impl Conversation {
             messages: messages.into_iter().collect(),
         }
     }
+
+    /// Number of messages in the conversation.
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// The last `n` messages, or all of them if there are fewer.
+    pub fn last_n_messages(&self, n: usize) -> &[Message] {
+        &self.messages[self.messages.len().saturating_sub(n)..]
+    }
+
+    /// The first `n` messages, or all of them if there are fewer.
+    pub fn first_n_messages(&self, n: usize) -> &[Message] {
+        &self.messages[..n.min(self.messages.len())]
+    }
+
+    /// Keep only the last `n` messages.
+    pub fn into_last_n_messages(mut self, n: usize) -> Self {
+        let skip = self.messages.len().saturating_sub(n);
+        self.messages.drain(..skip);
+        self
+    }
+
+    /// Keep only the first `n` messages.
+    pub fn into_first_n_messages(mut self, n: usize) -> Self {
+        self.messages.truncate(n);
+        self
+    }
+
+    /// Number of messages authored by `role`.
+    pub fn message_count_by_role(&self, role: Role) -> usize {
+        self.messages
+            .iter()
+            .filter(|m| m.author.role == role)
+            .count()
+    }
+
+    /// Whether any message is in `channel`.
+    pub fn contains_channel(&self, channel: &str) -> bool {
+        self.messages
+            .iter()
+            .any(|m| m.channel.as_deref() == Some(channel))
+    }
+
+    /// All channels used by the messages of the conversation.
+    pub fn unique_channels(&self) -> HashSet<&str> {
+        self.messages
+            .iter()
+            .filter_map(|m| m.channel.as_deref())
+            .collect()
+    }
+
+    /// The messages in `channel`, in conversation order.
+    pub fn messages_in_channel<'a>(
+        &'a self,
+        channel: &'a str,
+    ) -> impl Iterator<Item = &'a Message> + 'a {
+        self.messages
+            .iter()
+            .filter(move |m| m.channel.as_deref() == Some(channel))
+    }
+
+    /// The first message with the given [`Message::id`].
+    pub fn find_message_by_id(&self, id: &str) -> Option<&Message> {
+        self.messages.iter().find(|m| m.id.as_deref() == Some(id))
+    }
+
+    /// Total byte length of all text content in the conversation.
+    ///
+    /// This does not tokenize anything; combine it with
+    /// [`Conversation::average_tokens_per_byte_estimate`] for a cheap size
+    /// estimate before rendering.
+    pub fn total_text_length(&self) -> usize {
+        self.messages
+            .iter()
+            .flat_map(|m| &m.content)
+            .map(|c| match c {
+                Content::Text(TextContent { text }) => text.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Aggregate counts over all messages of the conversation.
+    pub fn statistics(&self) -> ConversationStatistics {
+        let mut stats = ConversationStatistics::default();
+        for msg in &self.messages {
+            *stats
+                .message_count_by_role
+                .entry(msg.author.role.clone())
+                .or_default() += 1;
+            stats.total_text_bytes += msg
+                .content
+                .iter()
+                .map(|c| match c {
+                    Content::Text(TextContent { text }) => text.len(),
+                    _ => 0,
+                })
+                .sum::<usize>();
+            if msg.is_tool_call() {
+                stats.tool_call_count += 1;
+            } else if msg.is_tool_response() {
+                stats.tool_response_count += 1;
+            }
+            if let Some(channel) = &msg.channel {
+                *stats
+                    .channel_distribution
+                    .entry(channel.clone())
+                    .or_default() += 1;
+            }
+        }
+        stats
+    }
+
+    /// Format the conversation as Markdown for logs, test failures and
+    /// other debugging output. This does not tokenize anything.
+    ///
+    /// Every message gets a `## {role}` heading, followed by its author name,
+    /// recipient, channel and content type (if any) in a blockquote and then
+    /// its content. Code, JSON and system or developer content are put in
+    /// fenced code blocks; other text is kept as a plain paragraph.
+    pub fn to_markdown(&self) -> String {
+        let mut sections = Vec::with_capacity(self.messages.len());
+        for msg in &self.messages {
+            let mut blocks = vec![format!("## {}", msg.author.role)];
+            let annotations: Vec<String> = [
+                ("name", &msg.author.name),
+                ("recipient", &msg.recipient),
+                ("channel", &msg.channel),
+                ("content type", &msg.content_type),
+            ]
+            .into_iter()
+            .filter_map(|(label, value)| value.as_ref().map(|v| format!("> {label}: {v}")))
+            .collect();
+            if !annotations.is_empty() {
+                blocks.push(annotations.join("\n"));
+            }
+            for content in &msg.content {
+                blocks.push(match content {
+                    Content::Text(TextContent { text }) if msg.content_type_is_json() => {
+                        markdown_code_block("json", text)
+                    }
+                    Content::Text(TextContent { text }) if msg.content_type_is_code() => {
+                        markdown_code_block("", text)
+                    }
+                    Content::Text(TextContent { text }) => text.clone(),
+                    other => markdown_code_block(
+                        "json",
+                        &serde_json::to_string_pretty(other).unwrap_or_default(),
+                    ),
+                });
+            }
+            sections.push(blocks.join("\n\n"));
+        }
+        let mut out = sections.join("\n\n");
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Rough number of tokens per byte of English text.
+    pub fn average_tokens_per_byte_estimate() -> f32 {
+        0.25
+    }
+
+    /// Append a user message.
+    ///
+    /// Fails if the last message is already from the user, since consecutive
+    /// user turns should be merged into a single message instead.
+    pub fn with_user_message(mut self, content: impl Into<Content>) -> anyhow::Result<Self> {
+        if self
+            .messages
+            .last()
+            .is_some_and(|m| m.author.role == Role::User)
+        {
+            anyhow::bail!("conversation already ends with a user message");
+        }
+        self.messages
+            .push(Message::from_role_and_content(Role::User, content));
+        Ok(self)
+    }
+
+    /// Append an assistant message.
+    ///
+    /// Unlike [`Conversation::with_user_message`] this never fails: an
+    /// assistant turn may span several messages, e.g. an `analysis` message
+    /// followed by the `final` answer.
+    pub fn with_assistant_message(mut self, content: impl Into<Content>) -> Self {
+        self.messages
+            .push(Message::from_role_and_content(Role::Assistant, content));
+        self
+    }
+
+    /// Apply `f` to every message of this conversation.
+    pub fn map_messages<F>(self, f: F) -> Conversation
+    where
+        F: FnMut(Message) -> Message,
+    {
+        Conversation {
+            messages: self.messages.into_iter().map(f).collect(),
+        }
+    }
+
+    /// Like [`Conversation::map_messages`], but stops at the first error
+    /// returned by `f`.
+    pub fn try_map_messages<F, E>(self, f: F) -> Result<Conversation, E>
+    where
+        F: FnMut(Message) -> Result<Message, E>,
+    {
+        Ok(Conversation {
+            messages: self.messages.into_iter().map(f).collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Append a tool call and the tool's response to it.
+    ///
+    /// `call` must be an assistant message addressed to a tool and `response`
+    /// a message from the tool named by `call`'s recipient. On failure the
+    /// returned error wraps a [`ConversationBuildError`].
+    pub fn append_tool_exchange(
+        mut self,
+        call: Message,
+        response: Message,
+    ) -> anyhow::Result<Self> {
+        if !call.is_tool_call() {
+            return Err(ConversationBuildError::NotAToolCall.into());
+        }
+        if !response.is_tool_response() {
+            return Err(ConversationBuildError::NotAToolResponse.into());
+        }
+        if call.recipient.as_deref() != response.author.name.as_deref() {
+            return Err(ConversationBuildError::MismatchedTool {
+                recipient: call.recipient.unwrap_or_default(),
+                responder: response.author.name,
+            }
+            .into());
+        }
+        self.messages.push(call);
+        self.messages.push(response);
+        Ok(self)
+    }
+
+    /// Check the structural invariants of the conversation: a system
+    /// message, if any, must be the first message.
+    ///
+    /// On failure the returned error wraps a [`ConversationBuildError`].
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(index) = self
+            .messages
+            .iter()
+            .skip(1)
+            .position(|m| m.author.role == Role::System)
+        {
+            return Err(ConversationBuildError::SystemNotFirst { index: index + 1 }.into());
+        }
+        Ok(())
+    }
+
+    /// Check every message for problems that would make it render
+    /// incorrectly, returning all of them rather than stopping at the first.
+    ///
+    /// Only assistant and tool messages may carry a content type, tool
+    /// messages must name their tool, channels must be listed in the system
+    /// message's [`ChannelConfig`] if there is one, and every message needs
+    /// some non-empty content.
+    pub fn validation_errors(&self) -> Vec<ConversationValidationError> {
+        let valid_channels = self.messages.iter().find_map(|m| {
+            m.content.iter().find_map(|c| match c {
+                Content::SystemContent(sys) => sys.channel_config.as_ref(),
+                _ => None,
+            })
+        });
+        let mut errors = Vec::new();
+        for (message_index, message) in self.messages.iter().enumerate() {
+            let role = message.author.role.clone();
+            if let Some(content_type) = &message.content_type {
+                if !matches!(role, Role::Assistant | Role::Tool) {
+                    errors.push(ConversationValidationError::WrongContentTypeForRole {
+                        message_index,
+                        role: role.clone(),
+                        content_type: content_type.clone(),
+                    });
+                }
+            }
+            if role == Role::Tool && message.author.name.is_none() {
+                errors.push(ConversationValidationError::MissingToolName { message_index });
+            }
+            if let (Some(channel), Some(config)) = (&message.channel, valid_channels) {
+                if !config.valid_channels.is_empty() && !config.valid_channels.contains(channel) {
+                    errors.push(ConversationValidationError::ChannelNotInConfig {
+                        message_index,
+                        channel: channel.clone(),
+                        valid_channels: config.valid_channels.clone(),
+                    });
+                }
+            }
+            let is_empty = message.content.iter().all(|c| match c {
+                Content::Text(TextContent { text }) => text.is_empty(),
+                _ => false,
+            });
+            if is_empty {
+                errors.push(ConversationValidationError::EmptyContent { message_index });
+            }
+        }
+        errors
+    }
+
+    /// Merge every run of consecutive system messages into a single system
+    /// message, e.g. after assembling a prompt from a base configuration and
+    /// per-user or per-request overrides.
+    ///
+    /// Later messages take precedence. Model identities are concatenated
+    /// (one per line, skipping repeats), tool namespaces are merged with later
+    /// ones replacing earlier ones of the same name, custom sections are
+    /// concatenated and every other setting, such as the knowledge cutoff, is
+    /// taken from the last message that sets it. A setting equal to its
+    /// [`SystemContent::default`] value (as filled in by
+    /// [`SystemContent::new`]) does not replace one set by an earlier message.
+    /// The merged message keeps the author, channel and recipient of the first
+    /// message of the run.
+    ///
+    /// Fails if a system message that needs merging has anything but a
+    /// single [`SystemContent`] as its content.
+    pub fn with_all_system_content_merged(self) -> anyhow::Result<Conversation> {
+        fn single_system_content(content: &mut [Content]) -> Option<&mut SystemContent> {
+            match content {
+                [Content::SystemContent(sys)] => Some(sys),
+                _ => None,
+            }
+        }
+
+        let mut messages: Vec<Message> = Vec::with_capacity(self.messages.len());
+        for (index, mut message) in self.messages.into_iter().enumerate() {
+            let merge_into = messages
+                .last_mut()
+                .filter(|_| message.author.role == Role::System)
+                .filter(|previous| previous.author.role == Role::System);
+            let Some(previous) = merge_into else {
+                messages.push(message);
+                continue;
+            };
+            let Some(previous) = single_system_content(&mut previous.content) else {
+                anyhow::bail!(
+                    "system message before index {index} must have a single system content to be merged"
+                );
+            };
+            let Some(incoming) = single_system_content(&mut message.content) else {
+                anyhow::bail!(
+                    "system message at index {index} must have a single system content to be merged"
+                );
+            };
+            previous.merge_from(std::mem::take(incoming));
+        }
+        Ok(Conversation { messages })
+    }
+
+    /// Insert `message` at `index`, shifting later messages back, and
+    /// [`validate`](Self::validate) the result.
+    pub fn insert_at(mut self, index: usize, message: Message) -> anyhow::Result<Self> {
+        let len = self.messages.len();
+        if index > len {
+            return Err(ConversationBuildError::IndexOutOfBounds { index, len }.into());
+        }
+        self.messages.insert(index, message);
+        self.validate()?;
+        Ok(self)
+    }
+
+    /// Replace the message at `index` with `message` and
+    /// [`validate`](Self::validate) the result.
+    pub fn replace_at(mut self, index: usize, message: Message) -> anyhow::Result<Self> {
+        let len = self.messages.len();
+        let Some(slot) = self.messages.get_mut(index) else {
+            return Err(ConversationBuildError::IndexOutOfBounds { index, len }.into());
+        };
+        *slot = message;
+        self.validate()?;
+        Ok(self)
+    }
+
+    /// Qualify tool names that lack a namespace with the namespace that
+    /// declares them, e.g. `get_weather` becomes `functions.get_weather`.
+    ///
+    /// Applies to the recipients of tool calls and the author names of tool
+    /// responses. Namespaces are looked up in the tools of the conversation's
+    /// system and developer messages; names that are a namespace themselves,
+    /// such as `python`, or aren't declared by exactly one namespace are left
+    /// unchanged.
+    pub fn normalize_tool_recipients(mut self) -> Self {
+        let mut namespaces_by_tool: HashMap<String, Vec<String>> = HashMap::new();
+        let mut namespaces = Vec::new();
+        let declared = self
+            .messages
+            .iter()
+            .flat_map(|m| &m.content)
+            .filter_map(|c| match c {
+                Content::SystemContent(sys) => sys.tools.as_ref(),
+                Content::DeveloperContent(dev) => dev.tools.as_ref(),
+                Content::Text(_) => None,
+            });
+        for ns in declared.flat_map(|tools| tools.values()) {
+            namespaces.push(ns.name.clone());
+            for tool in &ns.tools {
+                namespaces_by_tool
+                    .entry(tool.name.clone())
+                    .or_default()
+                    .push(ns.name.clone());
+            }
+        }
+        self.qualify_tool_names(|name| {
+            if namespaces.iter().any(|ns| ns == name) {
+                return None;
+            }
+            match namespaces_by_tool.get(name).map(Vec::as_slice) {
+                Some([ns]) => Some(ns.clone()),
+                _ => None,
+            }
+        });
+        self
+    }
+
+    /// Prefix tool names that lack a namespace with `namespace`, see
+    /// [`Self::normalize_tool_recipients`].
+    pub fn normalize_tool_recipients_for_namespace(mut self, namespace: &str) -> Self {
+        self.qualify_tool_names(|_| Some(namespace.to_string()));
+        self
+    }
+
+    /// Prefix each unqualified tool call recipient and tool author name with
+    /// the namespace returned by `namespace_of`, if any.
+    fn qualify_tool_names<F>(&mut self, mut namespace_of: F)
+    where
+        F: FnMut(&str) -> Option<String>,
+    {
+        for msg in &mut self.messages {
+            let name = if msg.is_tool_call() {
+                msg.recipient.as_mut()
+            } else if msg.is_tool_response() {
+                msg.author.name.as_mut()
+            } else {
+                None
+            };
+            if let Some(name) = name.filter(|n| !n.contains('.')) {
+                if let Some(ns) = namespace_of(name) {
+                    *name = format!("{ns}.{name}");
+                }
+            }
+        }
+    }
+
+    /// Keep only the messages for which `f` returns `true`.
+    pub fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&Message) -> bool,
+    {
+        self.messages.retain(f);
+    }
+
+    /// Swap the messages at indices `i` and `j`.
+    ///
+    /// Panics if either index is out of bounds.
+    pub fn swap_messages(&mut self, i: usize, j: usize) {
+        self.messages.swap(i, j);
+    }
+
+    /// Reverse the order of the messages.
+    pub fn reverse_order(mut self) -> Self {
+        self.messages.reverse();
+        self
+    }
+
+    /// Sort the messages with the comparator `compare`. The sort is stable,
+    /// so messages that compare equal keep their relative order.
+    pub fn sort_messages_by<F>(&mut self, compare: F)
+    where
+        F: FnMut(&Message, &Message) -> std::cmp::Ordering,
+    {
+        self.messages.sort_by(compare);
+    }
+
+    /// Drop the messages for which `f` returns `true`, e.g.
+    /// `convo.without_messages(|m| m.channel.as_deref() == Some("analysis"))`.
+    pub fn without_messages<F>(mut self, mut f: F) -> Self
+    where
+        F: FnMut(&Message) -> bool,
+    {
+        self.messages.retain(|m| !f(m));
+        self
+    }
+
+    /// Keep the last `n` complete turns, plus all system and developer
+    /// messages regardless of their position.
+    ///
+    /// A turn starts at a user message and includes every following
+    /// non-privileged message up to the next user message. A trailing turn
+    /// that only consists of user messages, i.e. is still awaiting a
+    /// response, is always kept and doesn't count towards `n`.
+    pub fn truncate_to_last_n_turns(self, n: usize) -> Self {
+        let turns = self.turn_indices();
+        let Some(last_turn) = turns.iter().flatten().max().copied() else {
+            return self;
+        };
+        let last_turn_incomplete = self
+            .messages
+            .iter()
+            .zip(&turns)
+            .filter(|(_, t)| **t == Some(last_turn))
+            .all(|(m, _)| m.author.role == Role::User);
+        let complete_turns = if last_turn_incomplete {
+            last_turn
+        } else {
+            last_turn + 1
+        };
+        let first_kept = complete_turns.saturating_sub(n);
+        self.retain_turns(turns, |t| t >= first_kept)
+    }
+
+    /// Keep the first `n` turns, plus all system and developer messages
+    /// regardless of their position. See
+    /// [`Conversation::truncate_to_last_n_turns`] for what makes up a turn.
+    pub fn truncate_to_first_n_turns(self, n: usize) -> Self {
+        let turns = self.turn_indices();
+        self.retain_turns(turns, |t| t < n)
+    }
+
+    /// Index of the turn every message belongs to, `None` for system and
+    /// developer messages. Consecutive user messages share a turn, and
+    /// messages before the first user message form a turn of their own.
+    fn turn_indices(&self) -> Vec<Option<usize>> {
+        let mut turn: Option<usize> = None;
+        let mut has_response = false;
+        self.messages
+            .iter()
+            .map(|m| {
+                if m.author.role.is_privileged() {
+                    return None;
+                }
+                if m.author.role == Role::User {
+                    if turn.is_none() || has_response {
+                        turn = Some(turn.map_or(0, |t| t + 1));
+                        has_response = false;
+                    }
+                } else {
+                    turn.get_or_insert(0);
+                    has_response = true;
+                }
+                turn
+            })
+            .collect()
+    }
+
+    fn retain_turns<F>(mut self, turns: Vec<Option<usize>>, mut keep: F) -> Self
+    where
+        F: FnMut(usize) -> bool,
+    {
+        let mut turns = turns.into_iter();
+        self.messages
+            .retain(|_| turns.next().flatten().is_none_or(&mut keep));
+        self
+    }
+
+    /// Render this conversation for training and split the tokens into a
+    /// `(prompt_tokens, completion_tokens)` pair.
+    ///
+    /// The split happens right after the last `<|start|>assistant` header, so
+    /// the prompt matches what `render_conversation_for_completion` would
+    /// produce and the completion holds the rest of the final assistant turn.
+    pub fn token_split_for_training(
+        &self,
+        encoding: &HarmonyEncoding,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<(Vec<Rank>, Vec<Rank>)> {
+        let mut tokens = encoding.render_conversation_for_training(self, config)?;
+        let start = encoding.render_formatting_token(FormattingToken::Start)?;
+        let role = encoding
+            .tokenizer()
+            .encode_ordinary(Role::Assistant.as_str());
+        let split = (0..tokens.len())
+            .rev()
+            .find(|&i| tokens[i] == start && tokens[i + 1..].starts_with(&role))
+            .context("conversation does not contain an assistant message")?
+            + 1
+            + role.len();
+        let completion = tokens.split_off(split);
+        Ok((tokens, completion))
+    }
+}
+
+/// Summary of a conversation, see [`Conversation::statistics`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConversationStatistics {
+    pub message_count_by_role: HashMap<Role, usize>,
+    /// Total byte length of all text content.
+    pub total_text_bytes: usize,
+    /// Assistant messages addressed to a tool.
+    pub tool_call_count: usize,
+    /// Messages authored by a tool.
+    pub tool_response_count: usize,
+    pub channel_distribution: HashMap<String, usize>,
+}
+
+/// Wrap `text` in a fenced code block whose fence is longer than any run of
+/// backticks inside `text`.
+fn markdown_code_block(lang: &str, text: &str) -> String {
+    let longest_run = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest_run.max(2) + 1);
+    format!("{fence}{lang}\n{}\n{fence}", text.trim_end_matches('\n'))
+}
+
+impl std::ops::Index<usize> for Conversation {
+    type Output = Message;
+
+    fn index(&self, index: usize) -> &Message {
+        &self.messages[index]
+    }
 }
 
 impl<'a> IntoIterator for &'a Conversation {
@@ -533,4 +1758,172 @@ impl DeveloperContent {
         self = self.with_tools(ToolNamespaceConfig::new("functions", None, tools));
         self
     }
+
+    pub fn with_browser_tool(self) -> Self {
+        self.with_tools(ToolNamespaceConfig::browser())
+    }
+
+    pub fn with_code_interpreter_tool(self) -> Self {
+        self.with_tools(ToolNamespaceConfig::python())
+    }
+
+    /// Add one function tool per operation of an OpenAPI 3.x document.
+    ///
+    /// Tools are named after each operation's `operationId`; path, query and
+    /// header parameters as well as a JSON request body are merged into a
+    /// single object schema, with `#/components/schemas/...` references
+    /// resolved inline. Tools are appended to any existing `functions`
+    /// namespace.
+    pub fn with_json_schema_tools(
+        mut self,
+        openapi_components: &serde_json::Value,
+    ) -> anyhow::Result<Self> {
+        let tools = openapi_tools(openapi_components)?;
+        let existing = self.tools.as_mut().and_then(|map| map.get_mut("functions"));
+        match existing {
+            Some(ns) => ns.tools.extend(tools),
+            None => self = self.with_function_tools(tools),
+        }
+        Ok(self)
+    }
+}
+
+const OPENAPI_METHODS: &[&str] = &[
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+
+fn openapi_tools(doc: &serde_json::Value) -> anyhow::Result<Vec<ToolDescription>> {
+    let version = doc
+        .get("openapi")
+        .and_then(|v| v.as_str())
+        .context("OpenAPI document is missing the `openapi` version field")?;
+    if !version.starts_with("3.") {
+        anyhow::bail!("unsupported OpenAPI version {version}, expected 3.x");
+    }
+    let empty = serde_json::Map::new();
+    let schemas = match doc.pointer("/components/schemas") {
+        None => &empty,
+        Some(v) => v
+            .as_object()
+            .context("`components.schemas` must be an object")?,
+    };
+    let paths = doc
+        .get("paths")
+        .and_then(|v| v.as_object())
+        .context("OpenAPI document is missing a `paths` object")?;
+
+    let mut tools = Vec::new();
+    for (path, item) in paths {
+        let item = item
+            .as_object()
+            .with_context(|| format!("path item {path} must be an object"))?;
+        let shared_params = item.get("parameters");
+        for method in OPENAPI_METHODS {
+            let Some(op) = item.get(*method) else {
+                continue;
+            };
+            let name = op
+                .get("operationId")
+                .and_then(|v| v.as_str())
+                .with_context(|| format!("operation {method} {path} has no operationId"))?;
+            let description = op
+                .get("summary")
+                .or_else(|| op.get("description"))
+                .and_then(|v| v.as_str())
+                .unwrap_or_default();
+            let parameters = openapi_operation_schema(op, shared_params, schemas)
+                .with_context(|| format!("invalid schema for operation {name}"))?;
+            tools.push(ToolDescription::new(name, description, parameters));
+        }
+    }
+    Ok(tools)
+}
+
+fn openapi_operation_schema(
+    op: &serde_json::Value,
+    shared_params: Option<&serde_json::Value>,
+    schemas: &serde_json::Map<String, serde_json::Value>,
+) -> anyhow::Result<Option<serde_json::Value>> {
+    let mut properties = serde_json::Map::new();
+    let mut required = Vec::new();
+
+    let params = shared_params
+        .into_iter()
+        .chain(op.get("parameters"))
+        .map(|v| v.as_array().context("`parameters` must be an array"))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    for param in params.into_iter().flatten() {
+        let param = resolve_openapi_refs(param, schemas, 0)?;
+        let name = param
+            .get("name")
+            .and_then(|v| v.as_str())
+            .context("parameter is missing a name")?;
+        let mut schema = param
+            .get("schema")
+            .cloned()
+            .unwrap_or_else(|| serde_json::json!({}));
+        if let (Some(desc), Some(obj)) = (param.get("description"), schema.as_object_mut()) {
+            obj.entry("description").or_insert_with(|| desc.clone());
+        }
+        if param.get("required").and_then(|v| v.as_bool()) == Some(true) {
+            required.push(serde_json::Value::from(name));
+        }
+        properties.insert(name.to_string(), schema);
+    }
+
+    if let Some(body) = op.pointer("/requestBody/content/application~1json/schema") {
+        let body = resolve_openapi_refs(body, schemas, 0)?;
+        match body.get("properties").and_then(|v| v.as_object()) {
+            Some(body_props) => {
+                properties.extend(body_props.clone());
+                if let Some(body_required) = body.get("required").and_then(|v| v.as_array()) {
+                    required.extend(body_required.iter().cloned());
+                }
+            }
+            None => {
+                properties.insert("body".to_string(), body);
+                required.push("body".into());
+            }
+        }
+    }
+
+    if properties.is_empty() {
+        return Ok(None);
+    }
+    let mut schema = serde_json::json!({ "type": "object", "properties": properties });
+    if !required.is_empty() {
+        schema["required"] = required.into();
+    }
+    Ok(Some(schema))
+}
+
+fn resolve_openapi_refs(
+    value: &serde_json::Value,
+    schemas: &serde_json::Map<String, serde_json::Value>,
+    depth: usize,
+) -> anyhow::Result<serde_json::Value> {
+    if depth > 32 {
+        anyhow::bail!("schema references nest too deeply (recursive schema?)");
+    }
+    match value {
+        serde_json::Value::Object(obj) => {
+            if let Some(reference) = obj.get("$ref").and_then(|v| v.as_str()) {
+                let target = reference
+                    .strip_prefix("#/components/schemas/")
+                    .and_then(|name| schemas.get(name))
+                    .with_context(|| format!("unresolved schema reference {reference}"))?;
+                return resolve_openapi_refs(target, schemas, depth + 1);
+            }
+            obj.iter()
+                .map(|(k, v)| Ok((k.clone(), resolve_openapi_refs(v, schemas, depth + 1)?)))
+                .collect::<anyhow::Result<_>>()
+                .map(serde_json::Value::Object)
+        }
+        serde_json::Value::Array(items) => items
+            .iter()
+            .map(|v| resolve_openapi_refs(v, schemas, depth + 1))
+            .collect::<anyhow::Result<_>>()
+            .map(serde_json::Value::Array),
+        other => Ok(other.clone()),
+    }
 }
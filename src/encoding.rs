@@ -1,5 +1,8 @@
 use crate::{
-    chat::{Author, Content, Message, ReasoningEffort, Role, SystemContent, TextContent},
+    chat::{
+        Author, Content, ContentType, Conversation, Message, ReasoningEffort, Role, SystemContent,
+        TextContent,
+    },
     tiktoken::{CoreBPE, Rank},
 };
 use tsify::Tsify;
@@ -19,6 +22,32 @@ pub struct ParsedHeader {
     content_type: Option<String>,
 }
 
+impl ParsedHeader {
+    /// Reconstructs the header as it appears in a rendered conversation
+    /// between `<|start|>` and `<|message|>`, e.g.
+    /// `assistant to=functions.get_weather<|channel|>commentary <|constrain|>json`.
+    ///
+    /// Useful to compare a parsed header against the original tokens when
+    /// debugging header parsing.
+    pub fn to_message_prefix_string(&self) -> String {
+        let mut out = match (&self.author.role, &self.author.name) {
+            (Role::Tool, Some(name)) => name.clone(),
+            (role, Some(name)) => format!("{role}:{name}"),
+            (role, None) => role.to_string(),
+        };
+        if let Some(recipient) = self.recipient.as_deref().filter(|r| *r != "all") {
+            out.push_str(&format!(" to={recipient}"));
+        }
+        if let Some(channel) = &self.channel {
+            out.push_str(&format!("<|channel|>{channel}"));
+        }
+        if let Some(content_type) = &self.content_type {
+            out.push_str(&format!(" {content_type}"));
+        }
+        out
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub(crate) enum RenderFormattingTokenError {
     #[error("tried to render unmapped formatting token {0}")]
@@ -38,7 +67,7 @@ pub(crate) enum RenderFormattingTokenError {
 /// by actual tokens from the tokenizers vocabulary.
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
-pub(crate) enum FormattingToken {
+pub enum FormattingToken {
     Start,
     Message,
     EndMessage,
@@ -54,6 +83,23 @@ pub(crate) enum FormattingToken {
 }
 
 impl FormattingToken {
+    pub fn all() -> &'static [Self] {
+        &[
+            Self::Start,
+            Self::Message,
+            Self::EndMessage,
+            Self::EndMessageDoneSampling,
+            Self::EndMessageAssistantToTool,
+            Self::Refusal,
+            Self::ConstrainedFormat,
+            Self::Channel,
+            Self::BeginUntrusted,
+            Self::EndUntrusted,
+            Self::MetaSep,
+            Self::MetaEnd,
+        ]
+    }
+
     fn as_str(&self) -> &str {
         match self {
             FormattingToken::Start => "<|start|>",
@@ -128,6 +174,152 @@ impl HarmonyEncoding {
         &self.tokenizer
     }
 
+    /// Replace the string representation of the given formatting tokens.
+    ///
+    /// Each new string must encode to exactly one token in the underlying
+    /// tokenizer; otherwise an error is returned and the encoding is dropped.
+    pub fn with_custom_format_mapping(
+        mut self,
+        overrides: HashMap<FormattingToken, String>,
+    ) -> anyhow::Result<Self> {
+        for (token, mapped) in &overrides {
+            let encoded = self.tokenizer.encode_with_special_tokens(mapped);
+            if encoded.len() != 1 {
+                anyhow::bail!(RenderFormattingTokenError::InvalidEncoding {
+                    token: *token,
+                    encoding: encoded,
+                });
+            }
+        }
+        self.format_token_mapping.extend(overrides);
+        Ok(self)
+    }
+
+    /// Returns the `<|constrain|>json` content type if `content` parses as
+    /// JSON, `None` otherwise.
+    pub fn infer_content_type_from_json(content: &str) -> Option<String> {
+        serde_json::from_str::<serde_json::Value>(content)
+            .is_ok()
+            .then(|| format!("{}json", FormattingToken::ConstrainedFormat))
+    }
+
+    /// Encode the JSON arguments of a tool call as ordinary text.
+    ///
+    /// Fails if `json_args` is not valid JSON or contains the text of a special
+    /// token, which would indicate an attempt to inject formatting tokens.
+    pub fn tokenize_tool_call_args(&self, json_args: &str) -> anyhow::Result<Vec<Rank>> {
+        serde_json::from_str::<serde_json::Value>(json_args)
+            .context("tool call arguments are not valid JSON")?;
+        if let Some(special) = self
+            .tokenizer
+            .encode_with_special_tokens(json_args)
+            .into_iter()
+            .find(|t| self.tokenizer.is_special_token(*t))
+        {
+            anyhow::bail!(
+                "tool call arguments contain the special token {}",
+                self.tokenizer.decode_utf8([special])?
+            );
+        }
+        Ok(self.tokenizer.encode_ordinary(json_args))
+    }
+
+    /// Decode `tokens` for display, e.g. model output that may end in the
+    /// middle of a multi-byte character. Unknown tokens are skipped and
+    /// invalid UTF-8 is replaced with U+FFFD.
+    pub fn decode_utf8_lossy(&self, tokens: &[Rank]) -> String {
+        self.tokenizer.decode_utf8_lossy(tokens)
+    }
+
+    /// Like [`Self::decode_utf8_lossy`], but replaces each invalid UTF-8
+    /// sequence with `replacement` instead of U+FFFD.
+    pub fn decode_utf8_with_fallback(&self, tokens: &[Rank], replacement: char) -> String {
+        let bytes = self.tokenizer.decode_bytes_lossy(tokens);
+        let mut out = String::with_capacity(bytes.len());
+        for chunk in bytes.utf8_chunks() {
+            out.push_str(chunk.valid());
+            if !chunk.invalid().is_empty() {
+                out.push(replacement);
+            }
+        }
+        out
+    }
+
+    /// Check that `text` survives a round trip through
+    /// [`CoreBPE::encode_ordinary`] and decoding, e.g. after adding custom
+    /// special tokens to the tokenizer.
+    ///
+    /// On a mismatch the returned error wraps a [`RoundtripError`].
+    pub fn verify_roundtrip(&self, text: &str) -> anyhow::Result<()> {
+        let tokens = self.tokenizer.encode_ordinary(text);
+        let decoded = self.tokenizer.decode_bytes(&tokens)?;
+        if decoded == text.as_bytes() {
+            return Ok(());
+        }
+        let differing_at_byte = text
+            .bytes()
+            .zip(&decoded)
+            .take_while(|(a, b)| a == *b)
+            .count();
+        Err(RoundtripError {
+            original: text.to_string(),
+            decoded: String::from_utf8_lossy(&decoded).into_owned(),
+            differing_at_byte,
+        }
+        .into())
+    }
+
+    /// Decode `tokens` into the longest valid UTF-8 prefix, returning the
+    /// trailing bytes of an incomplete multi-byte sequence separately.
+    ///
+    /// Bytes that can never form valid UTF-8 result in an error.
+    pub fn decode_partial_utf8(&self, tokens: &[Rank]) -> anyhow::Result<(String, Vec<u8>)> {
+        let mut bytes = self.tokenizer.decode_bytes(tokens)?;
+        let valid_len = match std::str::from_utf8(&bytes) {
+            Ok(s) => s.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => anyhow::bail!("invalid utf-8 sequence: {e}"),
+        };
+        let remaining = bytes.split_off(valid_len);
+        Ok((String::from_utf8(bytes)?, remaining))
+    }
+
+    /// Translate `tokens` from this encoding's vocabulary into `target`'s.
+    ///
+    /// Runs of ordinary tokens are decoded to text and re-encoded with
+    /// `target`; special tokens are mapped by their string representation and
+    /// result in an error if `target` has no special token of that name.
+    pub fn map_token_ids(
+        &self,
+        tokens: &[Rank],
+        target: &HarmonyEncoding,
+    ) -> anyhow::Result<Vec<Rank>> {
+        let target_specials = target.tokenizer.special_tokens();
+        let mut mapped = Vec::with_capacity(tokens.len());
+        for run in tokens.chunk_by(|a, b| {
+            !self.tokenizer.is_special_token(*a) && !self.tokenizer.is_special_token(*b)
+        }) {
+            let text = self.tokenizer.decode_utf8(run)?;
+            if !self.tokenizer.is_special_token(run[0]) {
+                mapped.extend(target.tokenizer.encode_ordinary(&text));
+                continue;
+            }
+            let Some(&special) = target_specials.get(text.as_str()) else {
+                anyhow::bail!(
+                    "special token {text} does not exist in encoding {}",
+                    target.name
+                );
+            };
+            mapped.extend(
+                target
+                    .tokenizer
+                    .encode(special, &HashSet::from([special]))
+                    .0,
+            );
+        }
+        Ok(mapped)
+    }
+
     pub fn stop_tokens(&self) -> anyhow::Result<HashSet<Rank>> {
         self.stop_formatting_tokens
             .iter()
@@ -155,6 +347,18 @@ impl HarmonyEncoding {
             })
             .collect()
     }
+
+    /// Union of [`Self::stop_tokens`] and [`Self::stop_tokens_for_assistant_actions`].
+    pub fn all_stop_tokens(&self) -> anyhow::Result<HashSet<Rank>> {
+        let mut tokens = self.stop_tokens()?;
+        tokens.extend(self.stop_tokens_for_assistant_actions()?);
+        Ok(tokens)
+    }
+
+    /// Whether `rank` ends generation in any mode.
+    pub fn is_stop_token(&self, rank: Rank) -> anyhow::Result<bool> {
+        Ok(self.all_stop_tokens()?.contains(&rank))
+    }
 }
 
 // Methods for rendering conversations
@@ -169,8 +373,156 @@ impl HarmonyEncoding {
     where
         I: IntoIterator<Item = &'a Message>,
         B: Extend<Rank>,
+    {
+        for rendered in self.render_messages(conversation, config)? {
+            let (_, tokens) = rendered?;
+            into.extend(tokens);
+        }
+        Ok(())
+    }
+
+    /// Renders a conversation and labels every token with the part of the
+    /// message it belongs to.
+    ///
+    /// Formatting tokens (including `<|channel|>` or `<|constrain|>` inside
+    /// headers) are labelled as such, the remaining tokens before `<|message|>`
+    /// as [`TokenAnnotation::Header`] and those after it as
+    /// [`TokenAnnotation::Content`] of the message author's role.
+    pub fn render_conversation_annotations<'a, I>(
+        &self,
+        conversation: I,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<Vec<(Rank, TokenAnnotation)>>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let formatting_tokens = self.formatting_tokens_by_rank();
+        let message_token = self.render_formatting_token(FormattingToken::Message)?;
+        let (messages, render_options) = self.messages_to_render(conversation, config)?;
+        let mut out = vec![];
+        for msg in messages {
+            let mut tokens = vec![];
+            self.render_into(msg, &mut tokens, Some(&render_options))?;
+            let mut in_content = false;
+            for token in tokens {
+                let annotation = match formatting_tokens.get(&token) {
+                    Some(t) => TokenAnnotation::FormattingToken(*t),
+                    None if in_content => TokenAnnotation::Content(msg.author.role.clone()),
+                    None => TokenAnnotation::Header,
+                };
+                in_content |= token == message_token;
+                out.push((token, annotation));
+            }
+        }
+        Ok(out)
+    }
+
+    /// Maps the rank of every renderable formatting token to that token.
+    ///
+    /// Several formatting tokens may share a rank (e.g. `MetaSep` and
+    /// `Channel`); the first one in `FormattingToken::all()` wins.
+    fn formatting_tokens_by_rank(&self) -> HashMap<Rank, FormattingToken> {
+        let mut formatting_tokens = HashMap::new();
+        for t in FormattingToken::all() {
+            if let Ok(rank) = self.render_formatting_token(*t) {
+                formatting_tokens.entry(rank).or_insert(*t);
+            }
+        }
+        formatting_tokens
+    }
+
+    /// Summarizes the composition of a rendered conversation, e.g. for
+    /// tracking prompt composition on evaluation dashboards.
+    ///
+    /// Ratios are relative to the total token count and `0.0` for an empty
+    /// conversation. Content tokens are those after `<|message|>` that are not
+    /// formatting tokens, see [`Self::render_conversation_annotations`].
+    pub fn render_summary(
+        &self,
+        conversation: &Conversation,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<RenderSummary> {
+        let formatting_ranks = self.formatting_tokens_by_rank();
+        let message_token = self.render_formatting_token(FormattingToken::Message)?;
+        let (messages, render_options) = self.messages_to_render(conversation, config)?;
+        let mut per_message_token_counts = Vec::with_capacity(messages.len());
+        let mut formatting_tokens = Vec::new();
+        let mut total_tokens = 0;
+        let mut system_tokens = 0;
+        let mut content_tokens = 0;
+        let mut tokens = vec![];
+        for msg in messages {
+            tokens.clear();
+            self.render_into(msg, &mut tokens, Some(&render_options))?;
+            total_tokens += tokens.len();
+            if msg.author.role == Role::System {
+                system_tokens += tokens.len();
+            }
+            per_message_token_counts.push(tokens.len());
+            let mut in_content = false;
+            for &token in &tokens {
+                if formatting_ranks.contains_key(&token) {
+                    let name = self.tokenizer.decode_utf8([token])?;
+                    if !formatting_tokens.contains(&name) {
+                        formatting_tokens.push(name);
+                    }
+                } else if in_content {
+                    content_tokens += 1;
+                }
+                in_content |= token == message_token;
+            }
+        }
+
+        let ratio = |count: usize| match total_tokens {
+            0 => 0.0,
+            total => count as f32 / total as f32,
+        };
+        Ok(RenderSummary {
+            total_tokens,
+            per_message_token_counts,
+            formatting_tokens,
+            system_token_ratio: ratio(system_tokens),
+            content_token_ratio: ratio(content_tokens),
+        })
+    }
+
+    /// Renders a conversation and pairs every token with its decoded string,
+    /// e.g. for log-probability analysis.
+    ///
+    /// Special tokens decode to their special form (e.g. `<|start|>`). Tokens
+    /// that are not valid UTF-8 on their own (partial multi-byte characters)
+    /// are decoded lossily.
+    pub fn render_for_logprob_analysis<'a, I>(
+        &self,
+        conversation: I,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<Vec<(Rank, String)>>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        self.render_conversation(conversation, config)?
+            .into_iter()
+            .map(|token| {
+                let bytes = self.tokenizer.decode_bytes([token])?;
+                Ok((token, String::from_utf8_lossy(&bytes).into_owned()))
+            })
+            .collect()
+    }
+
+    /// Selects the messages of a conversation that should be rendered,
+    /// applying the policy and analysis dropping from `config`.
+    fn messages_to_render<'a, I>(
+        &self,
+        conversation: I,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<(Vec<&'a Message>, RenderOptions)>
+    where
+        I: IntoIterator<Item = &'a Message>,
     {
         let messages: Vec<_> = conversation.into_iter().collect();
+        if let Some(policy) = config.and_then(|c| c.policy.as_ref()) {
+            policy.check(messages.iter().copied())?;
+        }
         let has_function_tools = messages.iter().any(|msg| {
             msg.content.iter().any(|c| {
                 if let Content::DeveloperContent(dev) = c {
@@ -190,6 +542,9 @@ impl HarmonyEncoding {
         });
         let render_options = RenderOptions {
             conversation_has_function_tools: has_function_tools,
+            channel_name_overrides: config
+                .map(|c| c.channel_name_overrides.clone())
+                .unwrap_or_default(),
         };
         let last_assistant_is_final = messages
             .iter()
@@ -207,17 +562,40 @@ impl HarmonyEncoding {
             .iter()
             .position(|msg| msg.channel.as_deref() == Some("final"));
 
-        let result = messages
-            .iter()
+        let messages = messages
+            .into_iter()
             .enumerate()
             .filter(|(idx, msg)| {
                 !(should_drop_analysis
                     && first_final_idx.is_some_and(|first| *idx < first)
                     && msg.channel.as_deref() == Some("analysis"))
             })
-            .try_for_each(|(_, msg)| self.render_into(msg, into, Some(&render_options)));
-        result?;
-        Ok(())
+            .map(|(_, msg)| msg)
+            .collect();
+        Ok((messages, render_options))
+    }
+
+    /// Renders the messages selected by [`Self::messages_to_render`] one at a
+    /// time, yielding each message with its tokens.
+    ///
+    /// Yields a [`TruncationError`] and stops once the rendered messages
+    /// exceed the `max_tokens` of `config`.
+    fn render_messages<'a, I>(
+        &self,
+        conversation: I,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<RenderedMessages<'_, 'a>>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let (messages, render_options) = self.messages_to_render(conversation, config)?;
+        Ok(RenderedMessages {
+            encoding: self,
+            messages: messages.into_iter(),
+            render_options,
+            max_tokens: config.and_then(|c| c.max_tokens),
+            total: 0,
+        })
     }
 
     /// Renders a conversation into a collection of tokens, adding the next turn role.
@@ -236,9 +614,7 @@ impl HarmonyEncoding {
     {
         let _config = config.unwrap_or(&RenderConversationConfig::default());
         self.render_conversation_into(conversation, into, config)?;
-        self.render_formatting_token_into(FormattingToken::Start, into)?;
-        self.render_text_into(next_turn_role.as_str(), into)?;
-        Ok(())
+        self.render_next_turn_header_into(&next_turn_role, into)
     }
 
     pub fn render_conversation_for_completion<'a, I>(
@@ -260,6 +636,468 @@ impl HarmonyEncoding {
         Ok(into)
     }
 
+    /// Renders a conversation for completion like
+    /// [`Self::render_conversation_for_completion`], additionally returning a
+    /// mask that is `true` at the positions of [`Self::stop_tokens`].
+    pub fn render_conversation_for_completion_with_stop_mask<'a, I>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<(Vec<Rank>, Vec<bool>)>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let stop_tokens = self.stop_tokens()?;
+        let tokens =
+            self.render_conversation_for_completion(conversation, next_turn_role, config)?;
+        let mask = tokens.iter().map(|t| stop_tokens.contains(t)).collect();
+        Ok((tokens, mask))
+    }
+
+    /// Renders a conversation for completion like
+    /// [`Self::render_conversation_for_completion`], additionally returning a
+    /// token type id per token: `0` for the conversation context and `1` for
+    /// the trailing `<|start|>{next_turn_role}` header.
+    pub fn render_conversation_for_completion_with_token_type_ids<'a, I>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<(Vec<Rank>, Vec<u8>)>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let mut tokens = vec![];
+        self.render_conversation_into(conversation, &mut tokens, config)?;
+        let context_len = tokens.len();
+        self.render_next_turn_header_into(&next_turn_role, &mut tokens)?;
+        let mut token_type_ids = vec![0u8; context_len];
+        token_type_ids.resize(tokens.len(), 1);
+        Ok((tokens, token_type_ids))
+    }
+
+    /// Renders a conversation for completion like
+    /// [`Self::render_conversation_for_completion`], additionally returning
+    /// the absolute position id of every token, i.e. `0..n`.
+    pub fn render_conversation_for_completion_with_positions<'a, I>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<(Vec<Rank>, Vec<u32>)>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let tokens =
+            self.render_conversation_for_completion(conversation, next_turn_role, config)?;
+        let positions = (0..u32::try_from(tokens.len())?).collect();
+        Ok((tokens, positions))
+    }
+
+    /// Renders a conversation for completion like
+    /// [`Self::render_conversation_for_completion`] and returns the tokens as
+    /// compact JSON, e.g.
+    /// `{"tokens":[200006,1428],"count":2,"encoding":"HarmonyGptOss"}`.
+    pub fn render_conversation_for_completion_json<'a, I>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<String>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        #[derive(serde::Serialize)]
+        struct RenderedJson<'a> {
+            tokens: &'a [Rank],
+            count: usize,
+            encoding: &'a str,
+        }
+
+        let tokens =
+            self.render_conversation_for_completion(conversation, next_turn_role, config)?;
+        Ok(serde_json::to_string(&RenderedJson {
+            tokens: &tokens,
+            count: tokens.len(),
+            encoding: &self.name,
+        })?)
+    }
+
+    /// Renders a conversation for completion into `buffer`, which is cleared
+    /// first, so callers can reuse its allocation across calls.
+    ///
+    /// Returns the number of tokens written. On error `buffer` may hold a
+    /// partial rendering.
+    pub fn render_conversation_for_completion_into_preallocated<'a, I>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        buffer: &mut Vec<Rank>,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<usize>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        buffer.clear();
+        self.render_conversation_for_completion_into(conversation, next_turn_role, buffer, config)?;
+        Ok(buffer.len())
+    }
+
+    /// Renders a conversation for completion like
+    /// [`Self::render_conversation_for_completion`], additionally reporting how
+    /// many tokens the messages of each role take up.
+    ///
+    /// The trailing `<|start|>{next_turn_role}` is not attributed to any role.
+    pub fn render_conversation_for_completion_with_role_token_counts<'a, I>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<(Vec<Rank>, HashMap<Role, usize>)>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let mut tokens = vec![];
+        let mut counts: HashMap<Role, usize> = HashMap::new();
+        for rendered in self.render_messages(conversation, config)? {
+            let (msg, message_tokens) = rendered?;
+            *counts.entry(msg.author.role.clone()).or_default() += message_tokens.len();
+            tokens.extend(message_tokens);
+        }
+        self.render_next_turn_header_into(&next_turn_role, &mut tokens)?;
+        Ok((tokens, counts))
+    }
+
+    /// Renders a conversation for completion like
+    /// [`Self::render_conversation_for_completion`], additionally returning the
+    /// token index at which each rendered message starts, followed by the
+    /// index of the trailing `<|start|>{next_turn_role}` header.
+    ///
+    /// Messages dropped while rendering, e.g. by `auto_drop_analysis`, have no
+    /// entry. Inference engines can use the boundaries to find where a
+    /// conversation starts to differ from a cached prefix.
+    pub fn render_conversation_for_completion_with_message_boundaries<'a, I>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<(Vec<Rank>, Vec<usize>)>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let mut tokens = vec![];
+        let mut boundaries = vec![];
+        for rendered in self.render_messages(conversation, config)? {
+            let (_, message_tokens) = rendered?;
+            boundaries.push(tokens.len());
+            tokens.extend(message_tokens);
+        }
+        boundaries.push(tokens.len());
+        self.render_next_turn_header_into(&next_turn_role, &mut tokens)?;
+        Ok((tokens, boundaries))
+    }
+
+    /// Renders a conversation for completion like
+    /// [`Self::render_conversation_for_completion`], additionally reporting how
+    /// much of the context window the result takes up.
+    pub fn render_conversation_for_completion_with_budget_info<'a, I>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<RenderResult>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let tokens =
+            self.render_conversation_for_completion(conversation, next_turn_role, config)?;
+        let used = tokens.len();
+        Ok(RenderResult {
+            tokens,
+            used,
+            available: self.n_ctx.saturating_sub(used),
+            utilization: match self.n_ctx {
+                0 => 0.0,
+                n_ctx => used as f32 / n_ctx as f32 * 100.0,
+            },
+        })
+    }
+
+    /// Renders a conversation for completion like
+    /// [`Self::render_conversation_for_completion`], failing if the result
+    /// doesn't fit in the encoding's context window.
+    ///
+    /// On overflow the returned error wraps a [`ContextWindowExceededError`].
+    pub fn render_conversation_for_completion_checked<'a, I>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<Vec<Rank>>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let tokens =
+            self.render_conversation_for_completion(conversation, next_turn_role, config)?;
+        if tokens.len() > self.n_ctx {
+            return Err(ContextWindowExceededError {
+                token_count: tokens.len(),
+                limit: self.n_ctx,
+            }
+            .into());
+        }
+        Ok(tokens)
+    }
+
+    /// Renders both conversations and computes the token range that changed
+    /// between them.
+    ///
+    /// The changed range is what remains after stripping the longest common
+    /// prefix and suffix of the two renderings.
+    pub fn diff_conversations(
+        &self,
+        before: &Conversation,
+        after: &Conversation,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<ConversationDiff> {
+        let before = self.render_conversation(before, config)?;
+        let after = self.render_conversation(after, config)?;
+        let prefix = before
+            .iter()
+            .zip(&after)
+            .take_while(|(a, b)| a == b)
+            .count();
+        if prefix == before.len() && prefix == after.len() {
+            return Ok(ConversationDiff {
+                first_changed_index: None,
+                tokens_removed: 0,
+                tokens_added: 0,
+            });
+        }
+        let suffix = before[prefix..]
+            .iter()
+            .rev()
+            .zip(after[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        Ok(ConversationDiff {
+            first_changed_index: Some(prefix),
+            tokens_removed: before.len() - prefix - suffix,
+            tokens_added: after.len() - prefix - suffix,
+        })
+    }
+
+    /// Renders a conversation for completion like
+    /// [`Self::render_conversation_for_completion`] and writes the tokens to
+    /// `writer`, each as a little-endian `u32`, as each message is rendered.
+    ///
+    /// Returns the number of tokens written. Bytes written before an error,
+    /// e.g. a [`TruncationError`] for `max_tokens`, are not taken back.
+    #[cfg(feature = "async")]
+    pub async fn render_conversation_for_completion_into_async_writer<'a, I, W>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        writer: &mut W,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<usize>
+    where
+        I: IntoIterator<Item = &'a Message>,
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        use tokio::io::AsyncWriteExt as _;
+
+        let mut total = 0;
+        for rendered in self.render_messages(conversation, config)? {
+            let (_, tokens) = rendered?;
+            total += tokens.len();
+            let bytes: Vec<u8> = tokens.iter().flat_map(|t| t.to_le_bytes()).collect();
+            writer.write_all(&bytes).await?;
+        }
+        let mut header = vec![];
+        self.render_next_turn_header_into(&next_turn_role, &mut header)?;
+        total += header.len();
+        let bytes: Vec<u8> = header.iter().flat_map(|t| t.to_le_bytes()).collect();
+        writer.write_all(&bytes).await?;
+        writer.flush().await?;
+        Ok(total)
+    }
+
+    /// Renders a conversation for completion like
+    /// [`Self::render_conversation_for_completion`] and sends the tokens
+    /// through `sender` in chunks of `chunk_size` as each message is rendered,
+    /// so consumers can start before the whole conversation is rendered. Only
+    /// the last chunk may be shorter.
+    ///
+    /// Fails if `chunk_size` is zero or the receiver is dropped. Chunks sent
+    /// before an error, e.g. a [`TruncationError`] for `max_tokens`, are not
+    /// taken back.
+    #[cfg(feature = "async")]
+    pub async fn render_conversation_for_completion_into_tokio_channel<'a, I>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        sender: tokio::sync::mpsc::Sender<Vec<Rank>>,
+        chunk_size: usize,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<()>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        anyhow::ensure!(chunk_size > 0, "chunk_size must be positive");
+        let mut pending = Vec::with_capacity(chunk_size);
+        for rendered in self.render_messages(conversation, config)? {
+            let (_, tokens) = rendered?;
+            pending.extend(tokens);
+            while pending.len() >= chunk_size {
+                let rest = pending.split_off(chunk_size);
+                sender.send(std::mem::replace(&mut pending, rest)).await?;
+            }
+        }
+        self.render_next_turn_header_into(&next_turn_role, &mut pending)?;
+        for chunk in pending.chunks(chunk_size) {
+            sender.send(chunk.to_vec()).await?;
+        }
+        Ok(())
+    }
+
+    /// Renders a conversation for completion like
+    /// [`Self::render_conversation_for_completion`] on tokio's blocking thread
+    /// pool, so async callers can await it without stalling the executor.
+    ///
+    /// The messages and config are cloned to move them onto the blocking
+    /// thread.
+    #[cfg(feature = "async")]
+    pub async fn render_conversation_for_completion_async<'a, I>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        config: Option<&'a RenderConversationConfig>,
+    ) -> anyhow::Result<Vec<Rank>>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let encoding = self.clone();
+        let messages: Vec<Message> = conversation.into_iter().cloned().collect();
+        let config = config.cloned();
+        tokio::task::spawn_blocking(move || {
+            encoding.render_conversation_for_completion(&messages, next_turn_role, config.as_ref())
+        })
+        .await?
+    }
+
+    /// Renders a conversation for completion with a partial assistant response.
+    ///
+    /// The conversation is followed by `<|start|>assistant<|message|>{prefill}`
+    /// without a closing `<|end|>`, so the model continues from the prefill.
+    pub fn render_conversation_for_completion_prefilled<'a, I>(
+        &self,
+        conversation: I,
+        prefill: &str,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<Vec<Rank>>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let mut into = vec![];
+        self.render_conversation_for_completion_into(
+            conversation,
+            Role::Assistant,
+            &mut into,
+            config,
+        )?;
+        self.render_formatting_token_into(FormattingToken::Message, &mut into)?;
+        self.render_text_into(prefill, &mut into)?;
+        Ok(into)
+    }
+
+    /// Number of tokens [`Self::render_conversation_for_completion`] would
+    /// produce, without collecting them.
+    pub fn count_tokens<'a, I>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<usize>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let mut counter = TokenCounter::default();
+        self.render_conversation_for_completion_into(
+            conversation,
+            next_turn_role,
+            &mut counter,
+            config,
+        )?;
+        Ok(counter.0)
+    }
+
+    /// Number of tokens [`Self::render_conversation`] would produce, without
+    /// collecting them.
+    pub fn count_conversation_tokens<'a, I>(
+        &self,
+        conversation: I,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<usize>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let mut counter = TokenCounter::default();
+        self.render_conversation_into(conversation, &mut counter, config)?;
+        Ok(counter.0)
+    }
+
+    /// Cheap estimate of the number of tokens `text` encodes to, without
+    /// running BPE: `ceil(char_count / 3.5)`.
+    ///
+    /// For English prose and code the estimate is typically within ±15% of
+    /// the actual o200k token count; it degrades for text with many digits,
+    /// symbols or non-Latin scripts. Useful to reject extremely long inputs
+    /// before encoding them.
+    pub fn estimate_token_count(&self, text: &str) -> usize {
+        (text.chars().count() as f64 / ESTIMATED_CHARS_PER_TOKEN).ceil() as usize
+    }
+
+    /// Estimate of [`Self::count_conversation_tokens`] that uses
+    /// [`Self::estimate_token_count`] for header fields and text content.
+    ///
+    /// System and developer content is rendered exactly since it is small and
+    /// largely made up of templated text.
+    pub fn estimate_conversation_token_count(&self, conversation: &Conversation) -> usize {
+        conversation
+            .messages
+            .iter()
+            .map(|message| {
+                // `<|start|>`, `<|message|>` and the closing token.
+                let mut count = 3 + self.estimate_token_count(message.author.role.as_str());
+                if let Some(name) = &message.author.name {
+                    count += self.estimate_token_count(name);
+                }
+                if let Some(recipient) = &message.recipient {
+                    count += self.estimate_token_count(recipient);
+                }
+                if let Some(channel) = &message.channel {
+                    count += 1 + self.estimate_token_count(channel);
+                }
+                if let Some(content_type) = &message.content_type {
+                    count += self.estimate_token_count(content_type);
+                }
+                for content in &message.content {
+                    count += match content {
+                        Content::Text(TextContent { text }) => self.estimate_token_count(text),
+                        _ => {
+                            let mut counter = TokenCounter::default();
+                            Render::<Content>::render(self, content, &mut counter, None)
+                                .map_or(0, |()| counter.0)
+                        }
+                    };
+                }
+                count
+            })
+            .sum()
+    }
+
     /// Render a conversation for training.
     ///
     /// If the last message in the conversation is an assistant message to the
@@ -286,6 +1124,83 @@ impl HarmonyEncoding {
         Ok(out)
     }
 
+    /// Render a conversation for evaluation, i.e. without the trailing
+    /// `<|start|>{role}` header that
+    /// [`Self::render_conversation_for_completion`] appends.
+    ///
+    /// The tokens end with the closing token of the last message. As in
+    /// [`Self::render_conversation_for_training`], that is `<|return|>` if
+    /// the last message is a final assistant answer, so the result matches
+    /// what the model samples for a complete response.
+    pub fn render_conversation_for_eval<'a, I>(
+        &self,
+        conversation: I,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<Vec<Rank>>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        self.render_conversation_for_training(conversation, config)
+    }
+
+    /// Render a conversation and pad it to `target_length` tokens for batched
+    /// training.
+    ///
+    /// Padding uses `pad_token`, or `<|endoftext|>` if `None`. The returned
+    /// attention mask is `false` for padding positions. Fails if the rendered
+    /// conversation is longer than `target_length`.
+    pub fn render_conversation_padded(
+        &self,
+        conversation: &Conversation,
+        target_length: usize,
+        pad_token: Option<Rank>,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<(Vec<Rank>, Vec<bool>)> {
+        let mut tokens = self.render_conversation(conversation, config)?;
+        if tokens.len() > target_length {
+            anyhow::bail!(
+                "rendered conversation has {} tokens, more than the target length {target_length}",
+                tokens.len()
+            );
+        }
+        let pad_token = match pad_token {
+            Some(token) => token,
+            None => self.special_token_rank("<|endoftext|>")?,
+        };
+        let mut mask = vec![true; tokens.len()];
+        mask.resize(target_length, false);
+        tokens.resize(target_length, pad_token);
+        Ok((tokens, mask))
+    }
+
+    /// Renders a conversation for completion like
+    /// [`Self::render_conversation_for_completion`], wrapped in
+    /// `<|startoftext|>` and `<|endoftext|>` for models that expect explicit
+    /// BOS/EOS tokens.
+    ///
+    /// Fails if the encoding has no such special tokens.
+    pub fn render_conversation_with_bos_eos<'a, I>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<Vec<Rank>>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let bos = self.special_token_rank("<|startoftext|>")?;
+        let eos = self.special_token_rank("<|endoftext|>")?;
+        let mut tokens = vec![bos];
+        self.render_conversation_for_completion_into(
+            conversation,
+            next_turn_role,
+            &mut tokens,
+            config,
+        )?;
+        tokens.push(eos);
+        Ok(tokens)
+    }
+
     /// Render a conversation without appending a new role.
     pub fn render_conversation<'a, I>(
         &self,
@@ -311,6 +1226,35 @@ impl HarmonyEncoding {
         Ok(out)
     }
 
+    /// Render `message` and return only the tokens after its header and the
+    /// first `skip_first_n_content_tokens` content tokens, e.g. to continue a
+    /// response whose prefix is already cached.
+    ///
+    /// The result includes the closing `<|end|>`-style token. Fails if
+    /// `skip_first_n_content_tokens` reaches past the end of the message.
+    pub fn render_message_as_continuation(
+        &self,
+        message: &Message,
+        skip_first_n_content_tokens: usize,
+        render_options: Option<&RenderOptions>,
+    ) -> anyhow::Result<Vec<Rank>> {
+        let mut tokens = self.render(message, render_options)?;
+        let message_token = self.render_formatting_token(FormattingToken::Message)?;
+        let content_start = tokens
+            .iter()
+            .position(|t| *t == message_token)
+            .context("rendered message has no <|message|> token")?
+            + 1;
+        let skip = content_start + skip_first_n_content_tokens;
+        if skip > tokens.len() {
+            anyhow::bail!(
+                "cannot skip {skip_first_n_content_tokens} content tokens of a message with {} tokens after its header",
+                tokens.len() - content_start
+            );
+        }
+        Ok(tokens.split_off(skip))
+    }
+
     /// Render a single message into the provided buffer.
     pub fn render_into<B>(
         &self,
@@ -323,41 +1267,272 @@ impl HarmonyEncoding {
     {
         Render::<Message>::render(self, message, into, render_options)
     }
+
+    /// Render `message` as a single line of text for debug logs, e.g.
+    /// `<|start|>assistant<|channel|>final<|message|>Hi!<|end|>`.
+    ///
+    /// Formatting tokens appear as their literal strings and newlines in the
+    /// content are escaped as `\n`. The output is not meant to be parsed.
+    pub fn render_message_for_log(
+        &self,
+        message: &Message,
+        render_options: Option<&RenderOptions>,
+    ) -> anyhow::Result<String> {
+        let tokens = self.render(message, render_options)?;
+        Ok(self
+            .tokenizer
+            .decode_utf8_lossy(&tokens)
+            .replace('\n', "\\n"))
+    }
+
+    /// Render only the header of a message, i.e. everything up to and
+    /// including `<|message|>`, without the content and closing token.
+    pub fn render_header_only(
+        &self,
+        message: &Message,
+        render_options: Option<&RenderOptions>,
+    ) -> anyhow::Result<Vec<Rank>> {
+        let mut out = vec![];
+        self.render_header_into(message, &mut out, render_options)?;
+        self.render_formatting_token_into(FormattingToken::Message, &mut out)?;
+        Ok(out)
+    }
+
+    /// Render only the content of a message, without `<|start|>`, the header,
+    /// `<|message|>` or the closing token.
+    pub fn render_message_body_only(&self, message: &Message) -> anyhow::Result<Vec<Rank>> {
+        let mut out = vec![];
+        self.render_body_into(message, &mut out, None)?;
+        Ok(out)
+    }
+
+    /// Number of tokens [`Self::render_message_body_only`] would produce.
+    pub fn count_message_body_tokens(&self, message: &Message) -> anyhow::Result<usize> {
+        let mut counter = TokenCounter::default();
+        self.render_body_into(message, &mut counter, None)?;
+        Ok(counter.0)
+    }
+
+    /// Render an assistant message calling `tool_name` (e.g.
+    /// `functions.get_weather`) with JSON arguments, ending in `<|call|>`.
+    ///
+    /// The channel defaults to `commentary`.
+    pub fn render_tool_call(
+        &self,
+        tool_name: &str,
+        args_json: &str,
+        channel: Option<&str>,
+    ) -> anyhow::Result<Vec<Rank>> {
+        let constrain = self
+            .mapped_format_token(FormattingToken::ConstrainedFormat)
+            .context("constrained format token is not mapped")?;
+        let message = Message::from_role_and_content(Role::Assistant, args_json)
+            .with_channel(channel.unwrap_or("commentary"))
+            .with_recipient(tool_name)
+            .with_content_type(format!("{constrain}json"));
+        self.render(&message, None)
+    }
+
+    /// Render the output of `tool_name` as a tool message addressed back to
+    /// the assistant, ending in `<|end|>`.
+    ///
+    /// The channel defaults to `commentary`.
+    pub fn render_tool_response(
+        &self,
+        tool_name: &str,
+        result_json: &str,
+        channel: Option<&str>,
+    ) -> anyhow::Result<Vec<Rank>> {
+        let message =
+            Message::from_author_and_content(Author::new(Role::Tool, tool_name), result_json)
+                .with_channel(channel.unwrap_or("commentary"))
+                .with_recipient("assistant");
+        self.render(&message, None)
+    }
+
+    /// The TypeScript declaration of a single tool namespace as it appears in
+    /// the `# Tools` section of a rendered system or developer message, e.g.
+    /// for generating tool documentation.
+    pub fn render_tool_namespace_as_typescript(
+        &self,
+        namespace: &crate::chat::ToolNamespaceConfig,
+    ) -> String {
+        Self::template_tool_namespace(namespace)
+    }
+
+    /// The whole `# Tools` section for `namespaces` as it appears in a
+    /// rendered system or developer message.
+    pub fn render_tool_namespaces_as_typescript(
+        &self,
+        namespaces: &std::collections::BTreeMap<String, crate::chat::ToolNamespaceConfig>,
+    ) -> String {
+        Self::template_tools_section(namespaces)
+    }
 }
 
-// Rendering helper methods
-impl HarmonyEncoding {
-    fn mapped_format_token(&self, t: FormattingToken) -> Option<&str> {
-        self.format_token_mapping.get(&t).map(|s| s.as_str())
-    }
+// Rendering helper methods
+impl HarmonyEncoding {
+    /// The rank of the special token `token`, failing if the tokenizer
+    /// doesn't have it.
+    fn special_token_rank(&self, token: &str) -> anyhow::Result<Rank> {
+        match self.tokenizer.encode_with_special_tokens(token)[..] {
+            [rank] if self.tokenizer.is_special_token(rank) => Ok(rank),
+            _ => anyhow::bail!("encoding {} has no {token} token", self.name),
+        }
+    }
+
+    fn mapped_format_token(&self, t: FormattingToken) -> Option<&str> {
+        self.format_token_mapping.get(&t).map(|s| s.as_str())
+    }
+
+    /// Whether `token` has a mapping in this encoding and can be rendered.
+    pub fn format_token_is_mapped(&self, token: FormattingToken) -> bool {
+        self.format_token_mapping.contains_key(&token)
+    }
+
+    /// All formatting tokens that have no mapping in this encoding.
+    pub fn unmapped_format_tokens(&self) -> impl Iterator<Item = FormattingToken> + '_ {
+        FormattingToken::all()
+            .iter()
+            .copied()
+            .filter(|t| !self.format_token_is_mapped(*t))
+    }
+
+    pub(crate) fn render_formatting_token(
+        &self,
+        t: FormattingToken,
+    ) -> Result<Rank, RenderFormattingTokenError> {
+        let mapped = self
+            .mapped_format_token(t)
+            .ok_or(RenderFormattingTokenError::UnmappedToken(t))?;
+        let encoded = self.tokenizer.encode_with_special_tokens(mapped);
+        if encoded.len() != 1 {
+            return Err(RenderFormattingTokenError::InvalidEncoding {
+                token: t,
+                encoding: encoded,
+            });
+        }
+        Ok(encoded[0])
+    }
+
+    fn render_formatting_token_into<B>(
+        &self,
+        t: FormattingToken,
+        into: &mut B,
+    ) -> anyhow::Result<()>
+    where
+        B: Extend<Rank>,
+    {
+        let r = self.render_formatting_token(t)?;
+        into.extend(std::iter::once(r));
+        Ok(())
+    }
+
+    /// Renders the `<|start|>{role}` header that a conversation rendered for
+    /// completion ends with.
+    fn render_next_turn_header_into<B>(&self, role: &Role, into: &mut B) -> anyhow::Result<()>
+    where
+        B: Extend<Rank>,
+    {
+        self.render_formatting_token_into(FormattingToken::Start, into)?;
+        self.render_text_into(role.as_str(), into)
+    }
+
+    /// Renders `<|start|>` followed by the author, recipient, channel and
+    /// content type of `message`.
+    fn render_header_into<B>(
+        &self,
+        message: &Message,
+        into: &mut B,
+        render_options: Option<&RenderOptions>,
+    ) -> anyhow::Result<()>
+    where
+        B: Extend<Rank>,
+    {
+        self.render_formatting_token_into(FormattingToken::Start, into)?;
+
+        // render role then username
+        if message.is_tool_response() && message.author.name.is_none() {
+            anyhow::bail!("Tools should have a name!");
+        }
+        self.render_text_into(message.author_display(), into)?;
+
+        // next render the header recipient, if there is one
+        if let Some(recipient) = &message.recipient {
+            if recipient != "all" {
+                self.render_text_into(format!(" to={recipient}"), into)?;
+            }
+        }
+
+        // next header channel, possibly renamed by the render options
+        if let Some(channel) = &message.channel {
+            let channel = render_options
+                .and_then(|o| o.channel_name_overrides.get(channel))
+                .unwrap_or(channel);
+            self.render_formatting_token_into(FormattingToken::Channel, into)?;
+            self.render_text_into(channel, into)?;
+        }
 
-    fn render_formatting_token(
-        &self,
-        t: FormattingToken,
-    ) -> Result<Rank, RenderFormattingTokenError> {
-        let mapped = self
-            .mapped_format_token(t)
-            .ok_or(RenderFormattingTokenError::UnmappedToken(t))?;
-        let encoded = self.tokenizer.encode_with_special_tokens(mapped);
-        if encoded.len() != 1 {
-            return Err(RenderFormattingTokenError::InvalidEncoding {
-                token: t,
-                encoding: encoded,
-            });
+        // finally content type
+        match message.typed_content_type() {
+            ContentType::Plain => {}
+            // <|constrain|> is a unique case which needs to be tokenized as a special token
+            ContentType::ConstrainedJson
+                if self.format_token_is_mapped(FormattingToken::ConstrainedFormat) =>
+            {
+                self.render_text_into(" ", into)?;
+                self.render_formatting_token_into(FormattingToken::ConstrainedFormat, into)?;
+                self.render_text_into("json", into)?;
+            }
+            content_type => {
+                let content_type = content_type.as_str();
+                if let Some(rest) = self
+                    .mapped_format_token(FormattingToken::ConstrainedFormat)
+                    .and_then(|marker| content_type.strip_prefix(marker))
+                {
+                    // Render the space, then the constrain marker as a special token, then the rest as text (if any)
+                    self.render_text_into(" ", into)?;
+                    self.render_formatting_token_into(FormattingToken::ConstrainedFormat, into)?;
+                    if !rest.is_empty() {
+                        self.render_text_into(rest, into)?;
+                    }
+                } else {
+                    self.render_text_into(format!(" {content_type}"), into)?;
+                }
+            }
         }
-        Ok(encoded[0])
+        Ok(())
     }
 
-    fn render_formatting_token_into<B>(
+    /// Renders the content of `message`, i.e. everything between
+    /// `<|message|>` and the closing token.
+    fn render_body_into<B>(
         &self,
-        t: FormattingToken,
+        message: &Message,
         into: &mut B,
+        render_options: Option<&RenderOptions>,
     ) -> anyhow::Result<()>
     where
         B: Extend<Rank>,
     {
-        let r = self.render_formatting_token(t)?;
-        into.extend(std::iter::once(r));
+        for content in message.content.iter() {
+            // SystemContent is only allowed in system messages
+            if let crate::chat::Content::SystemContent(_) = content {
+                anyhow::ensure!(
+                    message.author.role == crate::chat::Role::System,
+                    "SystemContent may only appear in system messages, found in {:?}",
+                    message.author.role
+                );
+            }
+            if let crate::chat::Content::DeveloperContent(_) = content {
+                anyhow::ensure!(
+                    message.author.role == crate::chat::Role::Developer,
+                    "DeveloperContent may only appear in developer messages, found in {:?}",
+                    message.author.role
+                );
+            }
+            Render::<Content>::render(self, content, into, render_options)?;
+        }
         Ok(())
     }
 
@@ -386,6 +1561,53 @@ impl HarmonyEncoding {
         Ok(parser.into_messages())
     }
 
+    /// Re-render `messages` parsed from `original_tokens`, e.g. with
+    /// [`Self::parse_messages_from_completion_tokens`], and check that the
+    /// result is identical to `original_tokens`.
+    ///
+    /// Messages are rendered as for training, so a trailing final assistant
+    /// message ends in `<|return|>`. If `original_tokens` doesn't start with
+    /// `<|start|>`, i.e. the first header was parsed with a given role, the
+    /// leading `<|start|>{role}` is left out as well. On success the rendered
+    /// tokens are returned, otherwise the error wraps a
+    /// [`RerenderMismatchError`].
+    pub fn rerender_parsed_messages(
+        &self,
+        original_tokens: &[Rank],
+        messages: &[Message],
+    ) -> anyhow::Result<Vec<Rank>> {
+        let config = RenderConversationConfig {
+            auto_drop_analysis: false,
+            ..Default::default()
+        };
+        let mut rendered = self.render_conversation_for_training(messages, Some(&config))?;
+        let start = self.render_formatting_token(FormattingToken::Start)?;
+        if let Some(first) = messages.first() {
+            if original_tokens.first() != Some(&start) {
+                let mut prefix = vec![start];
+                self.render_text_into(first.author_display(), &mut prefix)?;
+                if rendered.starts_with(&prefix) {
+                    rendered.drain(..prefix.len());
+                }
+            }
+        }
+
+        let position = original_tokens
+            .iter()
+            .zip(&rendered)
+            .position(|(a, b)| a != b)
+            .unwrap_or(original_tokens.len().min(rendered.len()));
+        if original_tokens.len() != rendered.len() || position < rendered.len() {
+            return Err(RerenderMismatchError {
+                position,
+                expected: original_tokens.get(position).copied(),
+                actual: rendered.get(position).copied(),
+            }
+            .into());
+        }
+        Ok(rendered)
+    }
+
     /// Helper to convert a JSON schema (OpenAPI style) to a TypeScript type definition.
     fn json_schema_to_typescript(schema: &serde_json::Value, indent: &str) -> String {
         // Helper to check if this schema is an enum
@@ -741,45 +1963,124 @@ impl HarmonyEncoding {
     ) -> String {
         let mut tool_sections = Vec::<String>::new();
         tool_sections.push("# Tools".to_string());
-        for ns_config in tools.values() {
-            let mut tool_section_content = Vec::<String>::new();
-            tool_section_content.push(format!("## {}\n", ns_config.name));
-            if let Some(desc) = &ns_config.description {
-                for line in desc.lines() {
-                    if !ns_config.tools.is_empty() {
-                        tool_section_content.push(format!("// {line}"));
-                    } else {
-                        tool_section_content.push(line.to_string());
-                    }
+        tool_sections.extend(tools.values().map(Self::template_tool_namespace));
+        tool_sections.join("\n\n")
+    }
+
+    /// Templates a single namespace of the tools section, starting with its
+    /// `## {name}` heading.
+    fn template_tool_namespace(ns_config: &crate::chat::ToolNamespaceConfig) -> String {
+        let mut tool_section_content = Vec::<String>::new();
+        tool_section_content.push(format!("## {}\n", ns_config.name));
+        if let Some(desc) = &ns_config.description {
+            for line in desc.lines() {
+                if !ns_config.tools.is_empty() {
+                    tool_section_content.push(format!("// {line}"));
+                } else {
+                    tool_section_content.push(line.to_string());
                 }
             }
-            if !ns_config.tools.is_empty() {
-                tool_section_content.push(format!("namespace {} {{\n", ns_config.name));
-                for tool in &ns_config.tools {
-                    for line in tool.description.lines() {
-                        tool_section_content.push(format!("// {line}"));
-                    }
-                    if let Some(params) = &tool.parameters {
-                        let param_type = Self::json_schema_to_typescript(params, "");
-                        tool_section_content.push(format!(
-                            "type {} = (_: {}) => any;\n",
-                            tool.name, param_type
-                        ));
-                    } else {
-                        tool_section_content.push(format!("type {} = () => any;\n", tool.name));
-                    }
+        }
+        if let Some(rate_limit) = &ns_config.rate_limit {
+            tool_section_content.push(format!("// Rate limit: {rate_limit}"));
+        }
+        if !ns_config.tools.is_empty() {
+            tool_section_content.push(format!("namespace {} {{\n", ns_config.name));
+            for tool in &ns_config.tools {
+                for line in tool.description.lines() {
+                    tool_section_content.push(format!("// {line}"));
+                }
+                let mut signature = if let Some(params) = &tool.parameters {
+                    let param_type = Self::json_schema_to_typescript(params, "");
+                    format!("type {} = (_: {}) => any;", tool.name, param_type)
+                } else {
+                    format!("type {} = () => any;", tool.name)
+                };
+                for example in &tool.examples {
+                    signature.push_str("\n// Example: ");
+                    signature.push_str(&Self::template_tool_example(&tool.name, example));
                 }
-                tool_section_content.push(format!("}} // namespace {}", ns_config.name));
+                signature.push('\n');
+                tool_section_content.push(signature);
             }
-            tool_sections.push(tool_section_content.join("\n"));
+            tool_section_content.push(format!("}} // namespace {}", ns_config.name));
         }
-        tool_sections.join("\n\n")
+        tool_section_content.join("\n")
+    }
+
+    /// Formats a tool example as `name(input) => output`, see
+    /// [`crate::chat::ToolDescription::examples`].
+    fn template_tool_example(tool_name: &str, example: &serde_json::Value) -> String {
+        let (input, output) = match example.as_object() {
+            Some(obj) if obj.contains_key("input") => (&obj["input"], obj.get("output")),
+            _ => (example, None),
+        };
+        match output {
+            Some(output) => format!("{tool_name}({input}) => {output}"),
+            None => format!("{tool_name}({input})"),
+        }
+    }
+}
+
+/// Average number of characters per token used by
+/// [`HarmonyEncoding::estimate_token_count`], calibrated against o200k.
+const ESTIMATED_CHARS_PER_TOKEN: f64 = 3.5;
+
+/// Iterator returned by [`HarmonyEncoding::render_messages`].
+struct RenderedMessages<'e, 'a> {
+    encoding: &'e HarmonyEncoding,
+    messages: std::vec::IntoIter<&'a Message>,
+    render_options: RenderOptions,
+    max_tokens: Option<usize>,
+    total: usize,
+}
+
+impl<'a> RenderedMessages<'_, 'a> {
+    fn render_message(&mut self, msg: &'a Message) -> anyhow::Result<(&'a Message, Vec<Rank>)> {
+        let mut tokens = vec![];
+        self.encoding
+            .render_into(msg, &mut tokens, Some(&self.render_options))?;
+        self.total += tokens.len();
+        if let Some(max_tokens) = self.max_tokens.filter(|max| self.total > *max) {
+            return Err(TruncationError {
+                max_tokens,
+                dropped_messages: self.messages.len() + 1,
+            }
+            .into());
+        }
+        Ok((msg, tokens))
+    }
+}
+
+impl<'a> Iterator for RenderedMessages<'_, 'a> {
+    type Item = anyhow::Result<(&'a Message, Vec<Rank>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let msg = self.messages.next()?;
+        let rendered = self.render_message(msg);
+        if rendered.is_err() {
+            self.messages = Default::default();
+        }
+        Some(rendered)
+    }
+}
+
+/// Sink for rendering methods that only counts the tokens it receives.
+#[derive(Default)]
+struct TokenCounter(usize);
+
+impl Extend<Rank> for TokenCounter {
+    fn extend<T: IntoIterator<Item = Rank>>(&mut self, iter: T) {
+        self.0 += iter.into_iter().count();
     }
 }
 
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct RenderOptions {
     pub conversation_has_function_tools: bool,
+    /// Channel names to render in place of the ones set on messages, see
+    /// [`RenderConversationConfig::channel_name_overrides`].
+    pub channel_name_overrides: HashMap<String, String>,
 }
 
 trait Render<T: ?Sized> {
@@ -803,77 +2104,9 @@ impl Render<Message> for HarmonyEncoding {
     where
         B: Extend<Rank>,
     {
-        self.render_formatting_token_into(FormattingToken::Start, into)?;
-
-        // render role then username
-        if matches!(message.author.role, Role::Tool) {
-            // for tools we only put the name
-            if let Some(name) = &message.author.name {
-                self.render_text_into(name, into)?;
-            } else {
-                anyhow::bail!("Tools should have a name!");
-            }
-        } else {
-            // For users and assistants we put both the role, and optionally the user name.
-            self.render_text_into(message.author.role.as_str(), into)?;
-            if let Some(name) = &message.author.name {
-                self.render_text_into(format!(":{name}"), into)?;
-            }
-        };
-
-        // next render the header recipient, if there is one
-        if let Some(recipient) = &message.recipient {
-            if recipient != "all" {
-                self.render_text_into(format!(" to={recipient}"), into)?;
-            }
-        }
-
-        // next header channel
-        if let Some(channel) = &message.channel {
-            self.render_formatting_token_into(FormattingToken::Channel, into)?;
-            self.render_text_into(channel, into)?;
-        }
-
-        // finally content type
-        if let Some(content_type) = &message.content_type {
-            // <|constrain|> is a unique case which needs to be tokenized as a special token
-            if let Some(constrain_marker) =
-                self.mapped_format_token(FormattingToken::ConstrainedFormat)
-            {
-                if let Some(rest) = content_type.strip_prefix(constrain_marker) {
-                    // Render the space, then the constrain marker as a special token, then the rest as text (if any)
-                    self.render_text_into(" ", into)?;
-                    self.render_formatting_token_into(FormattingToken::ConstrainedFormat, into)?;
-                    if !rest.is_empty() {
-                        self.render_text_into(rest, into)?;
-                    }
-                } else {
-                    self.render_text_into(format!(" {content_type}"), into)?;
-                }
-            } else {
-                self.render_text_into(format!(" {content_type}"), into)?;
-            }
-        }
-
+        self.render_header_into(message, into, render_options)?;
         self.render_formatting_token_into(FormattingToken::Message, into)?;
-        for content in message.content.iter() {
-            // SystemContent is only allowed in system messages
-            if let crate::chat::Content::SystemContent(_) = content {
-                anyhow::ensure!(
-                    message.author.role == crate::chat::Role::System,
-                    "SystemContent may only appear in system messages, found in {:?}",
-                    message.author.role
-                );
-            }
-            if let crate::chat::Content::DeveloperContent(_) = content {
-                anyhow::ensure!(
-                    message.author.role == crate::chat::Role::Developer,
-                    "DeveloperContent may only appear in developer messages, found in {:?}",
-                    message.author.role
-                );
-            }
-            Render::<Content>::render(self, content, into, render_options)?;
-        }
+        self.render_body_into(message, into, render_options)?;
 
         // If there is a tool call we should render a tool call token
         if message.author.role == crate::chat::Role::Assistant && message.recipient.is_some() {
@@ -969,6 +2202,14 @@ impl Render<SystemContent> for HarmonyEncoding {
             }
         }
 
+        if sys.content_type.is_some() || sys.response_format.is_some() {
+            let format = match &sys.response_format {
+                Some(schema) => Self::json_schema_to_typescript(schema, ""),
+                None => "Respond with valid JSON.".to_string(),
+            };
+            sections.push(format!("# Response format\n{format}"));
+        }
+
         if let Some(channel_config) = &sys.channel_config {
             if !channel_config.valid_channels.is_empty() {
                 let channels_str = channel_config.valid_channels.join(", ");
@@ -985,6 +2226,10 @@ impl Render<SystemContent> for HarmonyEncoding {
                 sections.push(channels_header);
             }
         }
+
+        for (title, body) in &sys.custom_sections {
+            sections.push(format!("# {title}\n{body}"));
+        }
         let formatted = sections.join("\n\n");
         self.render_text_into(&formatted, into)?;
         Ok(())
@@ -1033,6 +2278,9 @@ pub struct StreamableParser {
     stop_tokens: HashSet<Rank>,
     last_content_delta: Option<String>,
     undecoded_tokens: Vec<Rank>,
+    undecoded_emitted_len: usize,
+    current_message_bytes: usize,
+    completed_message_bytes: usize,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -1051,6 +2299,25 @@ impl StreamableParser {
     /// Create a new streaming parser starting with the given role.
     pub fn new(encoding: HarmonyEncoding, role: Option<Role>) -> anyhow::Result<Self> {
         let stop_tokens = encoding.stop_tokens()?;
+        Self::new_with_stop_tokens(encoding, role, stop_tokens)
+    }
+
+    /// Like [`Self::new`], but ends messages at `stop_tokens` instead of
+    /// [`HarmonyEncoding::stop_tokens`], e.g. to stop at `<|return|>` but
+    /// parse `<|end|>` as content.
+    ///
+    /// Fails if any of `stop_tokens` is not a token of `encoding`.
+    pub fn new_with_stop_tokens(
+        encoding: HarmonyEncoding,
+        role: Option<Role>,
+        stop_tokens: HashSet<Rank>,
+    ) -> anyhow::Result<Self> {
+        if let Some(unknown) = stop_tokens
+            .iter()
+            .find(|t| encoding.tokenizer.decode_bytes([**t]).is_err())
+        {
+            anyhow::bail!("stop token {unknown} is not part of the encoding");
+        }
         let (state, next_role) = match role {
             Some(role) => (
                 StreamState::Header {
@@ -1069,6 +2336,9 @@ impl StreamableParser {
             stop_tokens,
             last_content_delta: None,
             undecoded_tokens: Vec::new(),
+            undecoded_emitted_len: 0,
+            current_message_bytes: 0,
+            completed_message_bytes: 0,
         })
     }
 
@@ -1144,17 +2414,20 @@ impl StreamableParser {
                         true
                     } else {
                         self.undecoded_tokens.push(token);
-                        // some tokens might not appropriately decode on their own. If they don't
-                        // we will collect them until they eventually decode
-                        match self
-                            .encoding
-                            .tokenizer()
-                            .decode_utf8(&self.undecoded_tokens)
-                        {
-                            Ok(decoded) => {
-                                content_tokens.extend(self.undecoded_tokens.iter().copied());
-                                self.last_content_delta = Some(decoded);
-                                self.undecoded_tokens.clear();
+                        // some tokens end in the middle of a multi-byte character. We emit
+                        // whatever already decodes and hold on to the tokens until the
+                        // trailing bytes are complete.
+                        match self.encoding.decode_partial_utf8(&self.undecoded_tokens) {
+                            Ok((decoded, remaining)) => {
+                                let delta = decoded[self.undecoded_emitted_len..].to_string();
+                                self.current_message_bytes += delta.len();
+                                if remaining.is_empty() {
+                                    content_tokens.append(&mut self.undecoded_tokens);
+                                    self.undecoded_emitted_len = 0;
+                                } else {
+                                    self.undecoded_emitted_len = decoded.len();
+                                }
+                                self.last_content_delta = (!delta.is_empty()).then_some(delta);
                             }
                             Err(_) => {
                                 self.last_content_delta = None;
@@ -1168,18 +2441,27 @@ impl StreamableParser {
                     true
                 };
                 if is_eos {
-                    let text = self.encoding.tokenizer().decode_utf8(content_tokens)?;
+                    let mut text = self.encoding.tokenizer().decode_utf8(&*content_tokens)?;
+                    if let Ok((pending, _)) =
+                        self.encoding.decode_partial_utf8(&self.undecoded_tokens)
+                    {
+                        text.push_str(&pending);
+                    }
                     let message = Message {
                         author: header.author.clone(),
                         recipient: header.recipient.clone(),
                         channel: header.channel.clone(),
                         content_type: header.content_type.clone(),
                         content: vec![Content::Text(TextContent { text })],
+                        id: None,
                     };
                     self.messages.push(message);
                     self.state = StreamState::ExpectStart;
                     self.last_content_delta = None;
                     self.undecoded_tokens.clear();
+                    self.undecoded_emitted_len = 0;
+                    self.completed_message_bytes += self.current_message_bytes;
+                    self.current_message_bytes = 0;
                 }
             }
         }
@@ -1333,15 +2615,28 @@ impl StreamableParser {
     /// Return the textual content of the current message so far.
     pub fn current_content(&self) -> anyhow::Result<String> {
         match &self.state {
-            StreamState::Content { content_tokens, .. } => self
-                .encoding
-                .tokenizer()
-                .decode_utf8(content_tokens)
-                .map_err(|e| anyhow::anyhow!(e)),
+            StreamState::Content { content_tokens, .. } => {
+                let mut text = self
+                    .encoding
+                    .tokenizer()
+                    .decode_utf8(content_tokens)
+                    .map_err(|e| anyhow::anyhow!(e))?;
+                text.push_str(&self.undecoded_text());
+                Ok(text)
+            }
             _ => Ok(String::new()),
         }
     }
 
+    /// The already emitted text of tokens that are still waiting for the rest
+    /// of a multi-byte character.
+    fn undecoded_text(&self) -> String {
+        self.encoding
+            .decode_partial_utf8(&self.undecoded_tokens)
+            .map(|(text, _)| text)
+            .unwrap_or_default()
+    }
+
     /// Role of the current message if it has been parsed.
     pub fn current_role(&self) -> Option<Role> {
         match &self.state {
@@ -1350,6 +2645,41 @@ impl StreamableParser {
         }
     }
 
+    /// Role the parser was told to expect for the next message, as long as no
+    /// header tokens of that message have been processed yet.
+    pub fn peek_next_role(&self) -> Option<Role> {
+        match &self.state {
+            StreamState::ExpectStart => self.next_role.clone(),
+            StreamState::Header { header_tokens } if header_tokens.is_empty() => {
+                self.next_role.clone()
+            }
+            _ => None,
+        }
+    }
+
+    /// Content tokens held back because they end in an incomplete multi-byte
+    /// UTF-8 sequence.
+    pub fn undecoded_tokens(&self) -> &[Rank] {
+        &self.undecoded_tokens
+    }
+
+    /// Number of tokens in [`StreamableParser::undecoded_tokens`].
+    pub fn undecoded_token_count(&self) -> usize {
+        self.undecoded_tokens.len()
+    }
+
+    /// Number of content bytes decoded so far for the message currently being
+    /// parsed. Bytes of an incomplete trailing UTF-8 sequence are not counted.
+    pub fn byte_count_of_current_message(&self) -> usize {
+        self.current_message_bytes
+    }
+
+    /// Number of content bytes decoded across all parsed messages, including
+    /// the current one.
+    pub fn total_byte_count(&self) -> usize {
+        self.completed_message_bytes + self.current_message_bytes
+    }
+
     /// Current content type if known.
     pub fn current_content_type(&self) -> Option<String> {
         match &self.state {
@@ -1373,11 +2703,87 @@ impl StreamableParser {
         &self.messages
     }
 
+    /// The encoding the parser decodes tokens with.
+    pub fn encoding(&self) -> &HarmonyEncoding {
+        &self.encoding
+    }
+
     /// All tokens that were fed into the parser.
     pub fn tokens(&self) -> &[Rank] {
         &self.tokens
     }
 
+    /// Discard the message at `message_index` and everything parsed after it,
+    /// e.g. when the user edits an earlier message, so the parser can continue
+    /// from the preceding message boundary.
+    ///
+    /// The parser expects a `<|start|>` token next. `message_index` may equal
+    /// the number of parsed messages to only drop a partially parsed message.
+    pub fn rewind_to_message_boundary(&mut self, message_index: usize) -> anyhow::Result<()> {
+        if message_index > self.messages.len() {
+            anyhow::bail!(
+                "cannot rewind to message {message_index}, only {} messages were parsed",
+                self.messages.len()
+            );
+        }
+        let start = self
+            .encoding
+            .render_formatting_token(FormattingToken::Start)?;
+        // A parser created with a role starts in the header of the first
+        // message, without a preceding <|start|> token.
+        let implicit_first = self.tokens.first().is_some_and(|t| *t != start);
+        let mut message_starts = implicit_first.then_some(0).into_iter().chain(
+            self.tokens
+                .iter()
+                .enumerate()
+                .filter(|(_, t)| **t == start)
+                .map(|(i, _)| i),
+        );
+        let truncate_at = message_starts
+            .nth(message_index)
+            .unwrap_or(self.tokens.len());
+
+        self.tokens.truncate(truncate_at);
+        self.messages.truncate(message_index);
+        self.state = StreamState::ExpectStart;
+        self.next_role = None;
+        self.last_content_delta = None;
+        self.undecoded_tokens.clear();
+        self.undecoded_emitted_len = 0;
+        self.current_message_bytes = 0;
+        self.completed_message_bytes = self
+            .messages
+            .iter()
+            .flat_map(|m| &m.content)
+            .map(|c| match c {
+                Content::Text(TextContent { text }) => text.len(),
+                _ => 0,
+            })
+            .sum();
+        Ok(())
+    }
+
+    /// Replays the processed tokens, emitting
+    /// [`TokenOrMessage::MessageCompleted`] right after the stop token that
+    /// completed each message.
+    ///
+    /// Messages completed by [`StreamableParser::process_eos`] rather than a
+    /// stop token are emitted after the last token.
+    pub fn into_token_and_message_iter(self) -> impl Iterator<Item = TokenOrMessage> {
+        let mut messages = self.messages.into_iter();
+        let mut events = Vec::with_capacity(self.tokens.len() + messages.len());
+        for token in self.tokens {
+            events.push(TokenOrMessage::Token(token));
+            if self.stop_tokens.contains(&token) {
+                if let Some(message) = messages.next() {
+                    events.push(TokenOrMessage::MessageCompleted(message));
+                }
+            }
+        }
+        events.extend(messages.map(TokenOrMessage::MessageCompleted));
+        events.into_iter()
+    }
+
     /// Expose the current state as a JSON string for Python interop.
     pub fn state_json(&self) -> anyhow::Result<String> {
         #[derive(serde::Serialize)]
@@ -1429,12 +2835,182 @@ impl StreamableParser {
 #[derive(Tsify, serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct RenderConversationConfig {
     pub auto_drop_analysis: bool,
+    /// Constraints the conversation has to satisfy before it is rendered.
+    #[serde(default)]
+    pub policy: Option<ConversationPolicy>,
+    /// Maximum number of tokens the rendered messages may take up. Rendering
+    /// stops with a [`TruncationError`] at the first message that does not fit.
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    /// Channel names to render in place of the ones set on messages, e.g. to
+    /// render the `analysis` channel as `thinking` in experiments.
+    #[serde(default)]
+    pub channel_name_overrides: HashMap<String, String>,
 }
 
 impl Default for RenderConversationConfig {
     fn default() -> Self {
         Self {
             auto_drop_analysis: true,
+            policy: None,
+            max_tokens: None,
+            channel_name_overrides: HashMap::new(),
+        }
+    }
+}
+
+impl RenderConversationConfig {
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn with_channel_name_override(
+        mut self,
+        from: impl Into<String>,
+        to: impl Into<String>,
+    ) -> Self {
+        self.channel_name_overrides.insert(from.into(), to.into());
+        self
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error(
+    "conversation exceeds {max_tokens} tokens, {dropped_messages} message(s) were not rendered"
+)]
+pub struct TruncationError {
+    pub max_tokens: usize,
+    pub dropped_messages: usize,
+}
+
+/// Returned by [`HarmonyEncoding::render_conversation_for_completion_checked`]
+/// when the rendered conversation is longer than the context window.
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("rendered conversation has {token_count} tokens, exceeding the context window of {limit}")]
+pub struct ContextWindowExceededError {
+    pub token_count: usize,
+    pub limit: usize,
+}
+
+/// Returned by [`HarmonyEncoding::rerender_parsed_messages`] when the
+/// re-rendered tokens differ from the original ones. A token of `None` means
+/// that side ended before `position`.
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("re-rendered tokens differ at position {position}: expected {expected:?}, got {actual:?}")]
+pub struct RerenderMismatchError {
+    pub position: usize,
+    pub expected: Option<Rank>,
+    pub actual: Option<Rank>,
+}
+
+/// Returned by [`HarmonyEncoding::verify_roundtrip`] when decoding the
+/// encoded text doesn't give back the original. `decoded` is decoded lossily
+/// if it isn't valid UTF-8.
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("text does not round-trip, first difference at byte {differing_at_byte}: {original:?} decoded as {decoded:?}")]
+pub struct RoundtripError {
+    pub original: String,
+    pub decoded: String,
+    pub differing_at_byte: usize,
+}
+
+/// Rendered tokens together with context window usage, see
+/// [`HarmonyEncoding::render_conversation_for_completion_with_budget_info`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderResult {
+    pub tokens: Vec<Rank>,
+    /// Number of tokens in `tokens`.
+    pub used: usize,
+    /// Tokens left in the context window.
+    pub available: usize,
+    /// Percentage of the context window that is used; `0.0` for an empty
+    /// context window.
+    pub utilization: f32,
+}
+
+/// Composition of a rendered conversation, see
+/// [`HarmonyEncoding::render_summary`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderSummary {
+    pub total_tokens: usize,
+    /// Number of tokens of each rendered message, in conversation order.
+    pub per_message_token_counts: Vec<usize>,
+    /// Formatting tokens that occur, e.g. `<|start|>`, in order of first
+    /// occurrence.
+    pub formatting_tokens: Vec<String>,
+    /// Fraction of tokens that belong to system messages.
+    pub system_token_ratio: f32,
+    /// Fraction of tokens that are message content.
+    pub content_token_ratio: f32,
+}
+
+/// Token-level difference between two rendered conversations, see
+/// [`HarmonyEncoding::diff_conversations`].
+///
+/// Tokens from `first_changed_index` onwards differ, so any cached state
+/// (e.g. KV-cache entries) past that index has to be recomputed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConversationDiff {
+    /// Index of the first token that differs, `None` if both renderings are
+    /// identical.
+    pub first_changed_index: Option<usize>,
+    /// Number of tokens of `before` replaced by the change.
+    pub tokens_removed: usize,
+    /// Number of tokens of `after` inserted by the change.
+    pub tokens_added: usize,
+}
+
+/// Event yielded by [`StreamableParser::into_token_and_message_iter`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenOrMessage {
+    Token(Rank),
+    MessageCompleted(Message),
+}
+
+/// Label for a single token of a rendered conversation, see
+/// [`HarmonyEncoding::render_conversation_annotations`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenAnnotation {
+    /// Part of a message header, e.g. the role, recipient or channel name.
+    Header,
+    /// Part of the content of a message from the given role.
+    Content(Role),
+    /// A formatting token such as `<|start|>` or `<|end|>`.
+    FormattingToken(FormattingToken),
+}
+
+/// Constraints on the structure of a conversation, checked when rendering.
+#[derive(Tsify, serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ConversationPolicy {
+    /// Reject system and developer messages that come after the first user
+    /// message.
+    #[serde(default)]
+    pub no_privileged_after_user: bool,
+}
+
+impl ConversationPolicy {
+    pub fn check<'a, I>(&self, conversation: I) -> Result<(), PolicyViolationError>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let mut seen_user = false;
+        for (index, msg) in conversation.into_iter().enumerate() {
+            let role = &msg.author.role;
+            if self.no_privileged_after_user && seen_user && role.is_privileged() {
+                return Err(PolicyViolationError::PrivilegedAfterUser {
+                    index,
+                    role: role.clone(),
+                });
+            }
+            seen_user |= *role == Role::User;
         }
+        Ok(())
     }
 }
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum PolicyViolationError {
+    #[error("{role} message at index {index} comes after the first user message")]
+    PrivilegedAfterUser { index: usize, role: Role },
+}
@@ -6,11 +6,21 @@ mod registry;
 mod tiktoken;
 pub mod tiktoken_ext;
 
-pub use encoding::{HarmonyEncoding, StreamableParser};
+pub use encoding::{
+    ContextWindowExceededError, ConversationDiff, ConversationPolicy, FormattingToken,
+    HarmonyEncoding, ParsedHeader, PolicyViolationError, RenderConversationConfig, RenderResult,
+    RenderSummary, RerenderMismatchError, RoundtripError, StreamableParser, TokenAnnotation,
+    TokenOrMessage, TruncationError,
+};
+#[cfg(any(target_arch = "wasm32", feature = "blocking"))]
 pub use registry::load_harmony_encoding;
-pub use registry::HarmonyEncodingName;
+#[cfg(all(not(target_arch = "wasm32"), feature = "async"))]
+pub use registry::load_harmony_encoding_async;
+#[cfg(all(not(target_arch = "wasm32"), feature = "blocking"))]
+pub use registry::load_harmony_encoding_from_env;
+pub use registry::{list_available_encodings, HarmonyEncodingName};
 
-#[cfg(test)]
+#[cfg(all(test, feature = "blocking"))]
 pub mod tests;
 
 #[cfg(feature = "python-binding")]
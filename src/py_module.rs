@@ -26,11 +26,30 @@ use pyo3::Bound;
 create_exception!(openai_harmony, HarmonyError, PyRuntimeError);
 
 use crate::{
-    chat::{Message, Role, ToolNamespaceConfig},
-    encoding::{HarmonyEncoding, StreamableParser},
+    chat::{Message, Role, ToolDescription, ToolNamespaceConfig},
+    encoding::{HarmonyEncoding, RenderConversationConfig, StreamableParser},
     load_harmony_encoding, HarmonyEncodingName,
 };
 
+/// Converts the optional Python config dict into a `RenderConversationConfig`.
+///
+/// Only `auto_drop_analysis` (bool, default `True`) is read from the dict.
+fn parse_render_config(
+    config: Option<Bound<'_, PyDict>>,
+) -> PyResult<Option<RenderConversationConfig>> {
+    let Some(cfg_dict) = config else {
+        return Ok(None);
+    };
+    let auto_drop_analysis = cfg_dict
+        .get_item("auto_drop_analysis")?
+        .and_then(|v| v.extract().ok())
+        .unwrap_or(true);
+    Ok(Some(RenderConversationConfig {
+        auto_drop_analysis,
+        ..Default::default()
+    }))
+}
+
 /// A thin PyO3 wrapper around the Rust `HarmonyEncoding` struct.
 #[pyclass]
 struct PyHarmonyEncoding {
@@ -69,6 +88,12 @@ impl PyHarmonyEncoding {
         self.inner.name()
     }
 
+    /// The regex pattern the tokenizer uses to split text before applying BPE.
+    #[getter]
+    fn pattern(&self) -> &str {
+        self.inner.tokenizer().pattern()
+    }
+
     /// Render a conversation (in JSON format) for completion.
     ///
     /// Parameters
@@ -105,22 +130,115 @@ impl PyHarmonyEncoding {
             ))
         })?;
 
-        // Parse config
-        let rust_config = if let Some(cfg_dict) = config {
-            let auto_drop_analysis = cfg_dict
-                .get_item("auto_drop_analysis")?
-                .and_then(|v| v.extract().ok())
-                .unwrap_or(true);
-            Some(crate::encoding::RenderConversationConfig { auto_drop_analysis })
-        } else {
-            None
-        };
+        let rust_config = parse_render_config(config)?;
 
         self.inner
             .render_conversation_for_completion(&conversation, role, rust_config.as_ref())
             .map_err(|e| PyErr::new::<HarmonyError, _>(e.to_string()))
     }
 
+    /// Render many conversations for completion at once.
+    ///
+    /// The GIL is released and the conversations are rendered in parallel,
+    /// which avoids the per-call overhead of `render_conversation_for_completion`
+    /// in training pipelines. The result is in the same order as the input.
+    fn render_conversation_for_completion_batch(
+        &self,
+        py: Python<'_>,
+        conversations_json: Vec<String>,
+        next_turn_role: &str,
+        config: Option<Bound<'_, PyDict>>,
+    ) -> PyResult<Vec<Vec<u32>>> {
+        use rayon::prelude::*;
+
+        let conversations = conversations_json
+            .iter()
+            .enumerate()
+            .map(|(idx, json)| {
+                serde_json::from_str::<crate::chat::Conversation>(json).map_err(|e| {
+                    PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                        "invalid conversation JSON at index {idx}: {e}"
+                    ))
+                })
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+
+        let role = Role::try_from(next_turn_role).map_err(|_| {
+            PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "unknown role: {next_turn_role}"
+            ))
+        })?;
+
+        let rust_config = parse_render_config(config)?;
+
+        py.allow_threads(|| {
+            conversations
+                .par_iter()
+                .map(|conversation| {
+                    self.inner.render_conversation_for_completion(
+                        conversation,
+                        role.clone(),
+                        rust_config.as_ref(),
+                    )
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        })
+        .map_err(|e| PyErr::new::<HarmonyError, _>(e.to_string()))
+    }
+
+    /// Count the tokens `render_conversation_for_completion` would produce.
+    fn count_tokens(
+        &self,
+        py: Python<'_>,
+        conversation_json: &str,
+        next_turn_role: &str,
+        config: Option<Bound<'_, PyDict>>,
+    ) -> PyResult<usize> {
+        let conversation: crate::chat::Conversation = serde_json::from_str(conversation_json)
+            .map_err(|e| {
+                PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                    "invalid conversation JSON: {e}"
+                ))
+            })?;
+
+        let role = Role::try_from(next_turn_role).map_err(|_| {
+            PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                "unknown role: {next_turn_role}"
+            ))
+        })?;
+
+        let rust_config = parse_render_config(config)?;
+
+        py.allow_threads(|| {
+            self.inner
+                .count_tokens(&conversation, role, rust_config.as_ref())
+        })
+        .map_err(|e| PyErr::new::<HarmonyError, _>(e.to_string()))
+    }
+
+    /// Count the tokens `render_conversation` would produce.
+    fn count_conversation_tokens(
+        &self,
+        py: Python<'_>,
+        conversation_json: &str,
+        config: Option<Bound<'_, PyDict>>,
+    ) -> PyResult<usize> {
+        let conversation: crate::chat::Conversation = serde_json::from_str(conversation_json)
+            .map_err(|e| {
+                PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                    "invalid conversation JSON: {e}"
+                ))
+            })?;
+
+        let rust_config = parse_render_config(config)?;
+
+        py.allow_threads(|| {
+            self.inner
+                .count_conversation_tokens(&conversation, rust_config.as_ref())
+        })
+        .map_err(|e| PyErr::new::<HarmonyError, _>(e.to_string()))
+    }
+
     /// Render a conversation without appending a new role.
     fn render_conversation(
         &self,
@@ -134,15 +252,7 @@ impl PyHarmonyEncoding {
                 ))
             })?;
 
-        let rust_config = if let Some(cfg_dict) = config {
-            let auto_drop_analysis = cfg_dict
-                .get_item("auto_drop_analysis")?
-                .and_then(|v| v.extract().ok())
-                .unwrap_or(true);
-            Some(crate::encoding::RenderConversationConfig { auto_drop_analysis })
-        } else {
-            None
-        };
+        let rust_config = parse_render_config(config)?;
 
         self.inner
             .render_conversation(&conversation, rust_config.as_ref())
@@ -162,15 +272,7 @@ impl PyHarmonyEncoding {
                 ))
             })?;
 
-        let rust_config = if let Some(cfg_dict) = config {
-            let auto_drop_analysis = cfg_dict
-                .get_item("auto_drop_analysis")?
-                .and_then(|v| v.extract().ok())
-                .unwrap_or(true);
-            Some(crate::encoding::RenderConversationConfig { auto_drop_analysis })
-        } else {
-            None
-        };
+        let rust_config = parse_render_config(config)?;
 
         self.inner
             .render_conversation_for_training(&conversation, rust_config.as_ref())
@@ -183,7 +285,7 @@ impl PyHarmonyEncoding {
         message_json: &str,
         render_options: Option<Bound<'_, PyDict>>,
     ) -> PyResult<Vec<u32>> {
-        let message: crate::chat::Message = serde_json::from_str(message_json).map_err(|e| {
+        let message = crate::chat::Message::from_json(message_json).map_err(|e| {
             PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("invalid message JSON: {e}"))
         })?;
 
@@ -194,6 +296,7 @@ impl PyHarmonyEncoding {
                 .unwrap_or(false);
             Some(crate::encoding::RenderOptions {
                 conversation_has_function_tools,
+                ..Default::default()
             })
         } else {
             None
@@ -239,6 +342,11 @@ impl PyHarmonyEncoding {
             .map_err(|e| PyErr::new::<HarmonyError, _>(e.to_string()))
     }
 
+    /// Decode a sequence of tokens into text, replacing invalid UTF-8.
+    fn decode_utf8_lossy(&self, tokens: Vec<u32>) -> String {
+        self.inner.decode_utf8_lossy(&tokens)
+    }
+
     /// Decode a sequence of tokens into raw bytes using the underlying tokenizer.
     fn decode_bytes(&self, tokens: Vec<u32>) -> PyResult<Vec<u8>> {
         self.inner
@@ -262,6 +370,20 @@ impl PyHarmonyEncoding {
         Ok(self.inner.tokenizer().encode(text, &allowed_set).0)
     }
 
+    /// Encode text into tokens along with the `(start, end)` byte range of each token.
+    fn encode_with_offsets(
+        &self,
+        text: &str,
+        allowed_special: Option<Vec<String>>,
+    ) -> (Vec<u32>, Vec<(usize, usize)>) {
+        let allowed_vec = allowed_special.unwrap_or_default();
+        let allowed_set: std::collections::HashSet<&str> =
+            allowed_vec.iter().map(|s| s.as_str()).collect();
+        self.inner
+            .tokenizer()
+            .encode_with_offsets(text, &allowed_set)
+    }
+
     /// Return the list of special tokens for this tokenizer.
     fn special_tokens(&self) -> Vec<String> {
         self.inner
@@ -292,6 +414,20 @@ impl PyHarmonyEncoding {
             .map(|set| set.into_iter().collect())
             .map_err(|e| PyErr::new::<HarmonyError, _>(e.to_string()))
     }
+
+    /// Return the union of all stop tokens for the encoding.
+    fn all_stop_tokens(&self) -> PyResult<std::collections::HashSet<u32>> {
+        self.inner
+            .all_stop_tokens()
+            .map_err(|e| PyErr::new::<HarmonyError, _>(e.to_string()))
+    }
+
+    /// Return whether `token` is a stop token in any mode.
+    fn is_stop_token(&self, token: u32) -> PyResult<bool> {
+        self.inner
+            .is_stop_token(token)
+            .map_err(|e| PyErr::new::<HarmonyError, _>(e.to_string()))
+    }
 }
 
 #[pymethods]
@@ -317,6 +453,22 @@ impl PyStreamableParser {
             .map_err(|e| PyErr::new::<HarmonyError, _>(e.to_string()))
     }
 
+    /// Encode `text` with the parser's encoding and process the resulting
+    /// tokens. All special tokens are recognised unless `allowed_special`
+    /// restricts them.
+    fn process_str(&mut self, text: &str, allowed_special: Option<Vec<String>>) -> PyResult<()> {
+        let tokenizer = self.inner.encoding().tokenizer();
+        let allowed_set: std::collections::HashSet<&str> = match &allowed_special {
+            Some(allowed) => allowed.iter().map(|s| s.as_str()).collect(),
+            None => tokenizer.special_tokens(),
+        };
+        let (tokens, _) = tokenizer.encode(text, &allowed_set);
+        for token in tokens {
+            self.process(token)?;
+        }
+        Ok(())
+    }
+
     fn process_eos(&mut self) -> PyResult<()> {
         self.inner
             .process_eos()
@@ -396,12 +548,15 @@ fn openai_harmony(_py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     }
     m.add_function(pyo3::wrap_pyfunction!(load_harmony_encoding_py, m)?)?;
 
-    // Convenience functions to get the tool configs for the browser and python tools.
+    // Convenience functions to get the tool configs for the built-in tools.
     #[pyfunction]
     fn get_tool_namespace_config(py: Python<'_>, tool: &str) -> PyResult<PyObject> {
         let cfg = match tool {
             "browser" => ToolNamespaceConfig::browser(),
             "python" => ToolNamespaceConfig::python(),
+            "code_interpreter" => ToolNamespaceConfig::code_interpreter(),
+            "dalle" => ToolNamespaceConfig::dalle(),
+            "web_search" => ToolNamespaceConfig::web_search(),
             _ => {
                 return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
                     "Unknown tool namespace: {tool}"
@@ -418,5 +573,35 @@ fn openai_harmony(_py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     }
     m.add_function(pyo3::wrap_pyfunction!(get_tool_namespace_config, m)?)?;
 
+    // Validate the JSON arguments of a tool call against the tool's parameter schema.
+    #[pyfunction]
+    fn validate_tool_call(tool_json: &str, args_json: &str) -> PyResult<()> {
+        let tool: ToolDescription = serde_json::from_str(tool_json).map_err(|e| {
+            PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("invalid tool JSON: {e}"))
+        })?;
+        let args: serde_json::Value = serde_json::from_str(args_json)
+            .map_err(|e| PyErr::new::<HarmonyError, _>(format!("invalid tool arguments: {e}")))?;
+        tool.validate_against_schema(&args)
+            .map_err(|e| PyErr::new::<HarmonyError, _>(e.to_string()))
+    }
+    m.add_function(pyo3::wrap_pyfunction!(validate_tool_call, m)?)?;
+
+    // Override the base URL vocab files are downloaded from. Can only be set once.
+    #[pyfunction]
+    fn set_tiktoken_base_url(url: &str) -> PyResult<()> {
+        crate::tiktoken_ext::try_set_tiktoken_base_url(url).map_err(|current| {
+            PyErr::new::<HarmonyError, _>(format!(
+                "tiktoken base url has already been set to {current}"
+            ))
+        })
+    }
+    m.add_function(pyo3::wrap_pyfunction!(set_tiktoken_base_url, m)?)?;
+
+    #[pyfunction]
+    fn get_tiktoken_base_url() -> &'static str {
+        crate::tiktoken_ext::tiktoken_base_url()
+    }
+    m.add_function(pyo3::wrap_pyfunction!(get_tiktoken_base_url, m)?)?;
+
     Ok(())
 }
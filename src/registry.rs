@@ -5,6 +5,7 @@ use std::{
 
 use crate::{
     encoding::{FormattingToken, HarmonyEncoding},
+    tiktoken::CoreBPE,
     tiktoken_ext,
 };
 
@@ -13,6 +14,21 @@ pub enum HarmonyEncodingName {
     HarmonyGptOss,
 }
 
+impl HarmonyEncodingName {
+    pub fn all() -> &'static [HarmonyEncodingName] {
+        &[HarmonyEncodingName::HarmonyGptOss]
+    }
+
+    /// Like the [`std::str::FromStr`] impl, but ignores ASCII case, so e.g.
+    /// `harmonygptoss` and `HARMONYGPTOSS` are both accepted.
+    pub fn from_str_case_insensitive(s: &str) -> Option<HarmonyEncodingName> {
+        Self::all()
+            .iter()
+            .copied()
+            .find(|name| name.to_string().eq_ignore_ascii_case(s))
+    }
+}
+
 impl std::fmt::Display for HarmonyEncodingName {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -41,58 +57,88 @@ impl std::fmt::Debug for HarmonyEncodingName {
     }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
+/// Returns the names of all encodings that can be passed to
+/// [`load_harmony_encoding`].
+pub fn list_available_encodings() -> Vec<HarmonyEncodingName> {
+    HarmonyEncodingName::all().to_vec()
+}
+
+#[cfg(all(not(target_arch = "wasm32"), feature = "blocking"))]
 pub fn load_harmony_encoding(name: HarmonyEncodingName) -> anyhow::Result<HarmonyEncoding> {
-    match name {
-        HarmonyEncodingName::HarmonyGptOss => {
-            let n_ctx = 1_048_576; // 2^20
-            let max_action_length = 524_288; // 2^19
-            let encoding_ext = tiktoken_ext::Encoding::O200kHarmony;
-            Ok(HarmonyEncoding {
-                name: name.to_string(),
-                n_ctx,
-                tokenizer: Arc::new(encoding_ext.load()?),
-                tokenizer_name: encoding_ext.name().to_owned(),
-                max_message_tokens: n_ctx - max_action_length,
-                max_action_length,
-                format_token_mapping: make_mapping([
-                    (FormattingToken::Start, "<|start|>"),
-                    (FormattingToken::Message, "<|message|>"),
-                    (FormattingToken::EndMessage, "<|end|>"),
-                    (FormattingToken::EndMessageDoneSampling, "<|return|>"),
-                    (FormattingToken::Refusal, "<|refusal|>"),
-                    (FormattingToken::ConstrainedFormat, "<|constrain|>"),
-                    (FormattingToken::Channel, "<|channel|>"),
-                    (FormattingToken::EndMessageAssistantToTool, "<|call|>"),
-                    (FormattingToken::BeginUntrusted, "<|untrusted|>"),
-                    (FormattingToken::EndUntrusted, "<|end_untrusted|>"),
-                ]),
-                stop_formatting_tokens: HashSet::from([
-                    FormattingToken::EndMessageDoneSampling,
-                    FormattingToken::EndMessageAssistantToTool,
-                    FormattingToken::EndMessage,
-                ]),
-                stop_formatting_tokens_for_assistant_actions: HashSet::from([
-                    FormattingToken::EndMessageDoneSampling,
-                    FormattingToken::EndMessageAssistantToTool,
-                ]),
-            })
-        }
+    let tokenizer = tokenizer_encoding(name).load()?;
+    Ok(build_harmony_encoding(name, tokenizer))
+}
+
+const ENCODING_NAME_VAR: &str = "HARMONY_ENCODING_NAME";
+const VOCAB_PATH_VAR: &str = "HARMONY_VOCAB_PATH";
+const N_CTX_VAR: &str = "HARMONY_N_CTX";
+
+/// Load an encoding configured entirely through environment variables:
+///
+/// - `HARMONY_ENCODING_NAME`: the encoding to load, `HarmonyGptOss` if unset.
+/// - `HARMONY_VOCAB_PATH`: path of the vocab file, instead of downloading it
+///   or looking it up in `TIKTOKEN_ENCODINGS_BASE`.
+/// - `HARMONY_N_CTX`: context window size; half of it is reserved for the
+///   next action, as in the default configuration.
+#[cfg(all(not(target_arch = "wasm32"), feature = "blocking"))]
+pub fn load_harmony_encoding_from_env() -> anyhow::Result<HarmonyEncoding> {
+    use anyhow::Context as _;
+
+    let name = match std::env::var(ENCODING_NAME_VAR) {
+        Ok(name) => HarmonyEncodingName::from_str_case_insensitive(&name)
+            .with_context(|| format!("{ENCODING_NAME_VAR}: unknown encoding {name}"))?,
+        Err(_) => HarmonyEncodingName::HarmonyGptOss,
+    };
+    let tokenizer = match std::env::var(VOCAB_PATH_VAR) {
+        Ok(path) => tokenizer_encoding(name)
+            .load_from_path(&path)
+            .with_context(|| format!("{VOCAB_PATH_VAR}: failed to load {path}"))?,
+        Err(_) => tokenizer_encoding(name).load()?,
+    };
+    let mut encoding = build_harmony_encoding(name, tokenizer);
+    if let Ok(n_ctx) = std::env::var(N_CTX_VAR) {
+        let n_ctx: usize = n_ctx
+            .parse()
+            .with_context(|| format!("{N_CTX_VAR}: invalid context size {n_ctx}"))?;
+        encoding.n_ctx = n_ctx;
+        encoding.max_action_length = n_ctx / 2;
+        encoding.max_message_tokens = n_ctx - encoding.max_action_length;
     }
+    Ok(encoding)
+}
+
+/// Async variant of [`load_harmony_encoding`] that doesn't block the tokio
+/// runtime while the vocab file is downloaded and parsed.
+#[cfg(all(not(target_arch = "wasm32"), feature = "async"))]
+pub async fn load_harmony_encoding_async(
+    name: HarmonyEncodingName,
+) -> anyhow::Result<HarmonyEncoding> {
+    let tokenizer = tokenizer_encoding(name).load_async().await?;
+    Ok(build_harmony_encoding(name, tokenizer))
 }
 
 #[cfg(target_arch = "wasm32")]
 pub async fn load_harmony_encoding(name: HarmonyEncodingName) -> anyhow::Result<HarmonyEncoding> {
+    let tokenizer = tokenizer_encoding(name).load().await?;
+    Ok(build_harmony_encoding(name, tokenizer))
+}
+
+fn tokenizer_encoding(name: HarmonyEncodingName) -> tiktoken_ext::Encoding {
+    match name {
+        HarmonyEncodingName::HarmonyGptOss => tiktoken_ext::Encoding::O200kHarmony,
+    }
+}
+
+fn build_harmony_encoding(name: HarmonyEncodingName, tokenizer: CoreBPE) -> HarmonyEncoding {
     match name {
         HarmonyEncodingName::HarmonyGptOss => {
             let n_ctx = 1_048_576; // 2^20
             let max_action_length = 524_288; // 2^19
-            let encoding_ext = tiktoken_ext::Encoding::O200kHarmony;
-            Ok(HarmonyEncoding {
+            HarmonyEncoding {
                 name: name.to_string(),
                 n_ctx,
-                tokenizer: Arc::new(encoding_ext.load().await?),
-                tokenizer_name: encoding_ext.name().to_owned(),
+                tokenizer: Arc::new(tokenizer),
+                tokenizer_name: tokenizer_encoding(name).name().to_owned(),
                 max_message_tokens: n_ctx - max_action_length,
                 max_action_length,
                 format_token_mapping: make_mapping([
@@ -106,6 +152,7 @@ pub async fn load_harmony_encoding(name: HarmonyEncodingName) -> anyhow::Result<
                     (FormattingToken::EndMessageAssistantToTool, "<|call|>"),
                     (FormattingToken::BeginUntrusted, "<|untrusted|>"),
                     (FormattingToken::EndUntrusted, "<|end_untrusted|>"),
+                    (FormattingToken::MetaSep, "<|channel|>"),
                 ]),
                 stop_formatting_tokens: HashSet::from([
                     FormattingToken::EndMessageDoneSampling,
@@ -117,7 +164,7 @@ pub async fn load_harmony_encoding(name: HarmonyEncodingName) -> anyhow::Result<
                     FormattingToken::EndMessageAssistantToTool,
                 ]),
                 // conversation_has_function_tools: Arc::new(AtomicBool::new(false)),
-            })
+            }
         }
     }
 }
@@ -158,6 +158,45 @@ impl std::fmt::Display for DecodeError {
 
 impl std::error::Error for DecodeError {}
 
+/// Error returned by [`CoreBPE::extend_special_tokens`].
+#[derive(Debug, Clone)]
+pub enum ExtendError {
+    /// The rank is already used by an ordinary or special token.
+    RankInUse { token: String, rank: Rank },
+    /// The same rank was requested for more than one new token.
+    DuplicateRank { rank: Rank },
+    /// The same token was requested more than once.
+    DuplicateToken { token: String },
+    /// A special token with this text already exists.
+    TokenExists { token: String },
+    /// The regex matching special tokens could not be rebuilt.
+    Regex(String),
+}
+
+impl std::fmt::Display for ExtendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ExtendError::RankInUse { token, rank } => {
+                write!(f, "rank {rank} for {token} is already in use")
+            }
+            ExtendError::DuplicateRank { rank } => {
+                write!(f, "rank {rank} was requested more than once")
+            }
+            ExtendError::DuplicateToken { token } => {
+                write!(f, "special token {token} was requested more than once")
+            }
+            ExtendError::TokenExists { token } => {
+                write!(f, "special token {token} already exists")
+            }
+            ExtendError::Regex(message) => {
+                write!(f, "could not build special token regex: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExtendError {}
+
 const MAX_NUM_THREADS: usize = 128;
 
 #[derive(Clone)]
@@ -168,7 +207,8 @@ pub struct CoreBPE {
     special_tokens_decoder: HashMap<Rank, Vec<u8>>,
     regex_tls: Vec<Regex>,
     special_regex_tls: Vec<Regex>,
-    sorted_token_bytes: Vec<Vec<u8>>,
+    sorted_token_bytes: Vec<(Vec<u8>, Rank)>,
+    pattern: String,
 }
 
 impl CoreBPE {
@@ -218,6 +258,28 @@ impl CoreBPE {
         })
     }
 
+    /// Like [`CoreBPE::decode_bytes`], but silently skips unknown tokens
+    /// instead of failing.
+    pub fn decode_bytes_lossy(&self, tokens: &[Rank]) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(tokens.len() * 2);
+        for token in tokens {
+            if let Some(bytes) = self
+                .decoder
+                .get(token)
+                .or_else(|| self.special_tokens_decoder.get(token))
+            {
+                ret.extend(bytes);
+            }
+        }
+        ret
+    }
+
+    /// Like [`CoreBPE::decode_utf8`], but never fails: unknown tokens are
+    /// skipped and invalid UTF-8 is replaced with U+FFFD.
+    pub fn decode_utf8_lossy(&self, tokens: &[Rank]) -> String {
+        String::from_utf8_lossy(&self.decode_bytes_lossy(tokens)).into_owned()
+    }
+
     pub fn encode_ordinary(&self, text: &str) -> Vec<Rank> {
         // This is the core of the encoding logic; the other functions in here
         // just make things complicated :-)
@@ -233,6 +295,59 @@ impl CoreBPE {
         ret
     }
 
+    /// Encode `new_text` as a continuation of the text encoded by
+    /// `previous_tokens`, returning the tokens of the combined text as
+    /// [`CoreBPE::encode_ordinary`] would produce them.
+    ///
+    /// `new_text` may extend the last regex piece of the previous text (e.g.
+    /// `"hel"` followed by `"lo"`), so the tokens of that piece are encoded
+    /// again together with `new_text`; everything before it is reused.
+    /// Special tokens in `previous_tokens` are kept and act as boundaries.
+    /// If the tokens after the last special token don't decode to valid
+    /// UTF-8, `new_text` is encoded on its own and appended.
+    pub fn encode_ordinary_incremental(
+        &self,
+        previous_tokens: &[Rank],
+        new_text: &str,
+    ) -> Vec<Rank> {
+        let segment_start = previous_tokens
+            .iter()
+            .rposition(|t| self.special_tokens_decoder.contains_key(t))
+            .map_or(0, |idx| idx + 1);
+        let segment = &previous_tokens[segment_start..];
+        let mut ret = previous_tokens.to_vec();
+        let Ok(text) = self.decode_utf8(segment) else {
+            ret.extend(self.encode_ordinary(new_text));
+            return ret;
+        };
+        let last_piece_start = self
+            ._get_tl_regex()
+            .find_iter(&text)
+            .last()
+            .map_or(0, |mat| mat.unwrap().start());
+        // Piece boundaries are token boundaries, so keep every token that
+        // ends before the last piece starts.
+        let mut kept_len = 0;
+        let kept = segment
+            .iter()
+            .take_while(|t| {
+                let len = self.decoder.get(t).map_or(0, Vec::len);
+                let fits = kept_len + len <= last_piece_start;
+                if fits {
+                    kept_len += len;
+                }
+                fits
+            })
+            .count();
+        if !text.is_char_boundary(kept_len) {
+            ret.extend(self.encode_ordinary(new_text));
+            return ret;
+        }
+        ret.truncate(segment_start + kept);
+        ret.extend(self.encode_ordinary(&format!("{}{new_text}", &text[kept_len..])));
+        ret
+    }
+
     pub fn encode(&self, text: &str, allowed_special: &HashSet<&str>) -> (Vec<Rank>, usize) {
         let special_regex = self._get_tl_special_regex();
         let regex = self._get_tl_regex();
@@ -289,6 +404,28 @@ impl CoreBPE {
         (ret, last_piece_token_len)
     }
 
+    /// Like [`CoreBPE::encode`], but also returns the `(start, end)` byte range
+    /// of `text` that each token covers.
+    pub fn encode_with_offsets(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+    ) -> (Vec<Rank>, Vec<(usize, usize)>) {
+        let (tokens, _) = self.encode(text, allowed_special);
+        let mut offsets = Vec::with_capacity(tokens.len());
+        let mut start = 0;
+        for token in &tokens {
+            let len = self
+                .decoder
+                .get(token)
+                .or_else(|| self.special_tokens_decoder.get(token))
+                .map_or(0, |bytes| bytes.len());
+            offsets.push((start, start + len));
+            start += len;
+        }
+        (tokens, offsets)
+    }
+
     fn _increase_last_piece_token_len(
         &self,
         tokens: Vec<Rank>,
@@ -361,13 +498,13 @@ impl CoreBPE {
         // Separating this from the loop below helps with performance in a common case.
         let mut point = self
             .sorted_token_bytes
-            .partition_point(|x| x.as_slice() < unstable_bytes.as_slice());
+            .partition_point(|(x, _)| x.as_slice() < unstable_bytes.as_slice());
         while point < self.sorted_token_bytes.len()
-            && self.sorted_token_bytes[point].starts_with(&unstable_bytes)
+            && self.sorted_token_bytes[point]
+                .0
+                .starts_with(&unstable_bytes)
         {
-            completions.insert(vec![
-                self.encoder[self.sorted_token_bytes[point].as_slice()],
-            ]);
+            completions.insert(vec![self.sorted_token_bytes[point].1]);
             point += 1;
         }
 
@@ -379,12 +516,12 @@ impl CoreBPE {
             let suffix = &unstable_bytes[i..];
             let mut point = self
                 .sorted_token_bytes
-                .partition_point(|x| x.as_slice() < suffix);
+                .partition_point(|(x, _)| x.as_slice() < suffix);
             // TODO: Perf optimisation if suffix starts with " "?
             while point < self.sorted_token_bytes.len()
-                && self.sorted_token_bytes[point].starts_with(suffix)
+                && self.sorted_token_bytes[point].0.starts_with(suffix)
             {
-                let possibility = [prefix, self.sorted_token_bytes[point].as_slice()].concat();
+                let possibility = [prefix, self.sorted_token_bytes[point].0.as_slice()].concat();
                 let encoded = match std::str::from_utf8(&possibility) {
                     // Morally, this is byte_pair_encode(&possibility, &self.encoder)
                     // But we might have introduced a regex split which would prevent merges.
@@ -491,7 +628,8 @@ impl CoreBPE {
             .collect();
 
         // Clone because I don't know how to tell Rust I'm not going to change the map
-        let mut sorted_token_bytes: Vec<Vec<u8>> = encoder.keys().cloned().collect();
+        let mut sorted_token_bytes: Vec<(Vec<u8>, Rank)> =
+            encoder.iter().map(|(k, v)| (k.clone(), *v)).collect();
         sorted_token_bytes.sort();
 
         Ok(Self {
@@ -504,9 +642,52 @@ impl CoreBPE {
                 .map(|_| special_regex.clone())
                 .collect(),
             sorted_token_bytes,
+            pattern: pattern.to_string(),
         })
     }
 
+    /// The regex pattern used to split text into pieces before applying BPE.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Rank of the token formed by merging `token_a` and `token_b`, or `None`
+    /// if that merge is not part of the vocabulary.
+    ///
+    /// As in tiktoken, a lower rank means the merge is applied earlier.
+    pub fn merge_score(&self, token_a: Rank, token_b: Rank) -> Option<Rank> {
+        let a = self.decoder.get(&token_a)?;
+        let b = self.decoder.get(&token_b)?;
+        self.encoder
+            .get(&[a.as_slice(), b.as_slice()].concat())
+            .copied()
+    }
+
+    /// All ordinary tokens whose bytes are a prefix of `candidate`, shortest
+    /// first.
+    pub fn tokens_prefixing(&self, candidate: &[u8]) -> Vec<Rank> {
+        (1..=candidate.len())
+            .filter_map(|len| {
+                self.sorted_token_bytes
+                    .binary_search_by(|(x, _)| x.as_slice().cmp(&candidate[..len]))
+                    .ok()
+                    .map(|idx| self.sorted_token_bytes[idx].1)
+            })
+            .collect()
+    }
+
+    /// All ordinary tokens whose bytes start with `prefix`, in byte order.
+    pub fn tokens_prefixed_by(&self, prefix: &[u8]) -> Vec<Rank> {
+        let start = self
+            .sorted_token_bytes
+            .partition_point(|(x, _)| x.as_slice() < prefix);
+        self.sorted_token_bytes[start..]
+            .iter()
+            .take_while(|(x, _)| x.starts_with(prefix))
+            .map(|(_, rank)| *rank)
+            .collect()
+    }
+
     pub fn special_tokens(&self) -> HashSet<&str> {
         self.special_tokens_encoder
             .keys()
@@ -522,4 +703,71 @@ impl CoreBPE {
     pub fn is_special_token(&self, token: Rank) -> bool {
         self.special_tokens_decoder.contains_key(&token)
     }
+
+    /// Register additional special tokens. Fails without modifying the
+    /// tokenizer if any of the requested ranks is already in use.
+    pub fn add_special_tokens<I>(&mut self, tokens: I) -> Result<(), String>
+    where
+        I: IntoIterator<Item = (String, Rank)>,
+    {
+        self.extend_special_tokens(tokens)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Register additional special tokens, e.g. to add the harmony tokens to
+    /// a base encoding after it has been loaded.
+    ///
+    /// Fails without modifying the tokenizer if a rank is already in use, a
+    /// rank or token is requested twice, or a token with the same text
+    /// already exists.
+    pub fn extend_special_tokens<I>(&mut self, tokens: I) -> Result<(), ExtendError>
+    where
+        I: IntoIterator<Item = (String, Rank)>,
+    {
+        let mut unique_tokens: HashMap<String, Rank> = HashMap::default();
+        for (token, rank) in tokens {
+            if unique_tokens.contains_key(&token) {
+                return Err(ExtendError::DuplicateToken { token });
+            }
+            unique_tokens.insert(token, rank);
+        }
+        let tokens = unique_tokens;
+        let mut seen_ranks = HashSet::new();
+        for (token, &rank) in &tokens {
+            if self.decoder.contains_key(&rank) || self.special_tokens_decoder.contains_key(&rank) {
+                return Err(ExtendError::RankInUse {
+                    token: token.clone(),
+                    rank,
+                });
+            }
+            if !seen_ranks.insert(rank) {
+                return Err(ExtendError::DuplicateRank { rank });
+            }
+            if self.special_tokens_encoder.contains_key(token) {
+                return Err(ExtendError::TokenExists {
+                    token: token.clone(),
+                });
+            }
+        }
+
+        let mut special_tokens_encoder = self.special_tokens_encoder.clone();
+        special_tokens_encoder.extend(tokens);
+        let special_regex = {
+            let parts = special_tokens_encoder
+                .keys()
+                .map(|s| fancy_regex::escape(s))
+                .collect::<Vec<_>>();
+            Regex::new(&parts.join("|")).map_err(|e| ExtendError::Regex(e.to_string()))?
+        };
+
+        self.special_tokens_decoder = special_tokens_encoder
+            .iter()
+            .map(|(k, v)| (*v, k.as_bytes().to_vec()))
+            .collect();
+        self.special_tokens_encoder = special_tokens_encoder;
+        self.special_regex_tls = (0..MAX_NUM_THREADS)
+            .map(|_| special_regex.clone())
+            .collect();
+        Ok(())
+    }
 }
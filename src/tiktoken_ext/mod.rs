@@ -1,2 +1,5 @@
 mod public_encodings;
-pub use public_encodings::{set_tiktoken_base_url, Encoding};
+pub use public_encodings::{
+    extend_with_special_tokens, load_encoding_from_base64_str, load_tiktoken_vocab_from_base64_str,
+    set_tiktoken_base_url, tiktoken_base_url, try_set_tiktoken_base_url, Encoding, LoadError,
+};
@@ -1,7 +1,5 @@
 use std::{
     collections::HashMap,
-    fs::File,
-    io::{BufReader, BufWriter, Read as _, Write as _},
     path::{Path, PathBuf},
     sync::OnceLock,
 };
@@ -59,15 +57,24 @@ const DEFAULT_TIKTOKEN_BASE_URL: &str = "https://openaipublic.blob.core.windows.
 static TIKTOKEN_BASE_URL_OVERRIDE: OnceLock<String> = OnceLock::new();
 
 pub fn set_tiktoken_base_url(base_url: impl Into<String>) {
+    // ignore error if already set
+    let _ = try_set_tiktoken_base_url(base_url);
+}
+
+/// Like [`set_tiktoken_base_url`], but fails with the already configured base
+/// URL if it has been set before.
+pub fn try_set_tiktoken_base_url(base_url: impl Into<String>) -> Result<(), &'static str> {
     let mut base = base_url.into();
     if !base.ends_with('/') {
         base.push('/');
     }
-    // ignore error if already set
-    let _ = TIKTOKEN_BASE_URL_OVERRIDE.set(base);
+    TIKTOKEN_BASE_URL_OVERRIDE
+        .set(base)
+        .map_err(|_| tiktoken_base_url())
 }
 
-fn tiktoken_base_url() -> &'static str {
+/// The base URL vocab files are downloaded from.
+pub fn tiktoken_base_url() -> &'static str {
     TIKTOKEN_BASE_URL_OVERRIDE
         .get()
         .map(|s| s.as_str())
@@ -96,7 +103,7 @@ impl Encoding {
         None
     }
 
-    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg(all(not(target_arch = "wasm32"), feature = "blocking"))]
     pub fn load_from_name(name: impl AsRef<str>) -> Result<CoreBPE, LoadError> {
         let name = name.as_ref();
         Self::from_name(name)
@@ -121,9 +128,8 @@ impl Encoding {
         }
     }
 
-    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg(all(not(target_arch = "wasm32"), feature = "blocking"))]
     pub fn load(&self) -> Result<CoreBPE, LoadError> {
-        #[cfg(not(target_arch = "wasm32"))]
         let (vocab_file_path, check_hash) =
             if let Ok(base_dir) = std::env::var(TIKTOKEN_ENCODINGS_BASE_VAR) {
                 (PathBuf::from(base_dir).join(self.vocab_file_name()), true)
@@ -135,7 +141,51 @@ impl Encoding {
                     false,
                 )
             };
+        self.load_from_vocab_file(vocab_file_path, check_hash)
+    }
+
+    /// Load the encoding from a local copy of its vocab file at `path`, e.g.
+    /// one baked into a container image. The file's hash is verified.
+    #[cfg(all(not(target_arch = "wasm32"), feature = "blocking"))]
+    pub fn load_from_path(&self, path: impl AsRef<Path>) -> Result<CoreBPE, LoadError> {
+        self.load_from_vocab_file(path.as_ref().to_path_buf(), true)
+    }
+
+    /// Async counterpart of [`Encoding::load`]. The vocab file is downloaded
+    /// with the non-blocking `reqwest` client and parsed on tokio's blocking
+    /// thread pool, so this must be awaited from within a tokio runtime.
+    #[cfg(all(not(target_arch = "wasm32"), feature = "async"))]
+    pub async fn load_async(&self) -> Result<CoreBPE, LoadError> {
+        let (vocab_file_path, check_hash) =
+            if let Ok(base_dir) = std::env::var(TIKTOKEN_ENCODINGS_BASE_VAR) {
+                (PathBuf::from(base_dir).join(self.vocab_file_name()), true)
+            } else {
+                let url = self.public_vocab_file_url();
+                (
+                    download_or_find_cached_file_async(&url, Some(self.expected_hash()))
+                        .await
+                        .map_err(LoadError::DownloadOrLoadVocabFile)?,
+                    false,
+                )
+            };
+        let encoding = *self;
+        tokio::task::spawn_blocking(move || {
+            encoding.load_from_vocab_file(vocab_file_path, check_hash)
+        })
+        .await
+        .map_err(|e| LoadError::CoreBPECreationFailed(Box::new(e)))?
+    }
 
+    #[cfg(all(
+        not(target_arch = "wasm32"),
+        any(feature = "blocking", feature = "async")
+    ))]
+    fn load_from_vocab_file(
+        &self,
+        vocab_file_path: PathBuf,
+        check_hash: bool,
+    ) -> Result<CoreBPE, LoadError> {
+        let expected_hash = check_hash.then(|| self.expected_hash());
         match self {
             Self::O200kHarmony => {
                 let mut specials: Vec<(String, Rank)> = self
@@ -144,19 +194,7 @@ impl Encoding {
                     .map(|(s, r)| ((*s).to_string(), *r))
                     .collect();
                 specials.extend((200014..=201088).map(|id| (format!("<|reserved_{id}|>"), id)));
-                #[cfg(not(target_arch = "wasm32"))]
-                {
-                    load_encoding_from_file(
-                        vocab_file_path,
-                        check_hash.then(|| self.expected_hash()),
-                        specials,
-                        &self.pattern(),
-                    )
-                }
-                #[cfg(target_arch = "wasm32")]
-                {
-                    load_encoding_from_bytes(&vocab_bytes, None, specials, &self.pattern())
-                }
+                load_encoding_from_file(vocab_file_path, expected_hash, specials, &self.pattern())
             }
             Self::O200kBase => {
                 let mut specials: Vec<(String, Rank)> = self
@@ -165,40 +203,14 @@ impl Encoding {
                     .map(|(s, r)| ((*s).to_string(), *r))
                     .collect();
                 specials.extend((199998..=201088).map(|id| (format!("<|reserved_{id}|>"), id)));
-                #[cfg(not(target_arch = "wasm32"))]
-                {
-                    load_encoding_from_file(
-                        vocab_file_path,
-                        check_hash.then(|| self.expected_hash()),
-                        specials,
-                        &self.pattern(),
-                    )
-                }
-                #[cfg(target_arch = "wasm32")]
-                {
-                    load_encoding_from_bytes(&vocab_bytes, None, specials, &self.pattern())
-                }
-            }
-            _ => {
-                #[cfg(not(target_arch = "wasm32"))]
-                {
-                    load_encoding_from_file(
-                        vocab_file_path,
-                        check_hash.then(|| self.expected_hash()),
-                        self.special_tokens().iter().cloned(),
-                        &self.pattern(),
-                    )
-                }
-                #[cfg(target_arch = "wasm32")]
-                {
-                    load_encoding_from_bytes(
-                        &vocab_bytes,
-                        None,
-                        self.special_tokens().iter().cloned(),
-                        &self.pattern(),
-                    )
-                }
+                load_encoding_from_file(vocab_file_path, expected_hash, specials, &self.pattern())
             }
+            _ => load_encoding_from_file(
+                vocab_file_path,
+                expected_hash,
+                self.special_tokens().iter().cloned(),
+                &self.pattern(),
+            ),
         }
     }
 
@@ -432,9 +444,67 @@ where
     .map_err(LoadError::CoreBPECreationFailed)
 }
 
+/// Load a tiktoken vocab from an inline base64 string, i.e. a whole
+/// `.tiktoken` file encoded once more as base64, so small vocabularies can be
+/// embedded in a binary. Whitespace in `base64_encoded` is ignored and
+/// `expected_hash` is checked against the decoded vocab file.
+pub fn load_tiktoken_vocab_from_base64_str(
+    base64_encoded: &str,
+    expected_hash: Option<&str>,
+) -> std::result::Result<HashMap<Vec<u8>, Rank>, std::io::Error> {
+    let compact: String = base64_encoded
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect();
+    let vocab_bytes = BASE64_STANDARD.decode(compact).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("failed to decode base64 vocab: {e}"),
+        )
+    })?;
+    load_tiktoken_vocab(std::io::Cursor::new(vocab_bytes), expected_hash)
+}
+
+/// Like [`load_encoding_from_bytes`], but for a vocab embedded as a base64
+/// string; see [`load_tiktoken_vocab_from_base64_str`].
+pub fn load_encoding_from_base64_str<S, TS>(
+    base64_encoded: &str,
+    expected_hash: Option<&str>,
+    special_tokens: S,
+    pattern: &str,
+) -> Result<CoreBPE, LoadError>
+where
+    S: IntoIterator<Item = (TS, Rank)>,
+    TS: Into<String>,
+{
+    let encoder = load_tiktoken_vocab_from_base64_str(base64_encoded, expected_hash)
+        .map_err(LoadError::InvalidTiktokenVocabFile)?;
+    CoreBPE::new(
+        encoder,
+        special_tokens.into_iter().map(|(k, v)| (k.into(), v)),
+        pattern,
+    )
+    .map_err(LoadError::CoreBPECreationFailed)
+}
+
+/// Add `extras` as special tokens to an already loaded encoding.
+///
+/// This is the recommended way to build an extended encoding, e.g. a base
+/// encoding with additional model-specific tokens, instead of constructing a
+/// new [`CoreBPE`] from the vocab file. Fails without adding any token if a
+/// rank or token name is already in use.
+pub fn extend_with_special_tokens<I>(mut base: CoreBPE, extras: I) -> Result<CoreBPE, LoadError>
+where
+    I: IntoIterator<Item = (String, Rank)>,
+{
+    base.extend_special_tokens(extras)
+        .map_err(|e| LoadError::FailedToExtendEncoding(Box::new(e)))?;
+    Ok(base)
+}
+
 /// This returns the path to a file containing the data at `url`. If the file is
 /// cached, it is used. Otherwise, the file is downloaded and cached.
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(not(target_arch = "wasm32"), feature = "blocking"))]
 fn download_or_find_cached_file(
     url: &str,
     expected_hash: Option<&str>,
@@ -461,6 +531,38 @@ fn download_or_find_cached_file(
     Ok(cache_path)
 }
 
+/// Async counterpart of [`download_or_find_cached_file`].
+#[cfg(all(not(target_arch = "wasm32"), feature = "async"))]
+async fn download_or_find_cached_file_async(
+    url: &str,
+    expected_hash: Option<&str>,
+) -> Result<PathBuf, RemoteVocabFileError> {
+    let cache_dir = resolve_cache_dir()?;
+    let cache_path = resolve_cache_path(&cache_dir, url);
+    if let Ok(cached) = tokio::fs::read(&cache_path).await {
+        let computed_hash = format!("{:x}", Sha256::digest(&cached));
+        if expected_hash.is_none_or(|expected_hash| computed_hash == expected_hash) {
+            return Ok(cache_path);
+        }
+        let _ = tokio::fs::remove_file(&cache_path).await;
+    }
+    let bytes = load_remote_file_bytes(url).await?;
+    if let Some(expected_hash) = expected_hash {
+        let computed_hash = format!("{:x}", Sha256::digest(&bytes));
+        if computed_hash != expected_hash {
+            return Err(RemoteVocabFileError::HashMismatch {
+                file_url: url.to_string(),
+                expected_hash: expected_hash.to_string(),
+                computed_hash,
+            });
+        }
+    }
+    tokio::fs::write(&cache_path, &bytes)
+        .await
+        .map_err(|e| RemoteVocabFileError::IOError(format!("writing to file {cache_path:?}"), e))?;
+    Ok(cache_path)
+}
+
 #[cfg(target_arch = "wasm32")]
 async fn download_or_find_cached_file_bytes(
     url: &str,
@@ -506,10 +608,13 @@ fn resolve_cache_path(cache_dir: &Path, url: &str) -> PathBuf {
     cache_dir.join(cache_key)
 }
 
+#[cfg(feature = "blocking")]
 fn verify_file_hash(
     file_path: &Path,
     expected_hash: Option<&str>,
 ) -> Result<bool, RemoteVocabFileError> {
+    use std::{fs::File, io::BufReader};
+
     let Some(expected_hash) = expected_hash else {
         return Ok(true);
     };
@@ -526,8 +631,13 @@ fn verify_file_hash(
 
 /// Loads a remote file to `destination` and returns the computed hash of the
 /// file contents.
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(not(target_arch = "wasm32"), feature = "blocking"))]
 fn load_remote_file(url: &str, destination: &Path) -> Result<String, RemoteVocabFileError> {
+    use std::{
+        fs::File,
+        io::{BufWriter, Read as _, Write as _},
+    };
+
     let client = reqwest::blocking::Client::new();
     let mut response = client
         .get(url)
@@ -565,7 +675,7 @@ fn load_remote_file(_url: &str, _destination: &Path) -> Result<String, RemoteVoc
     ))
 }
 
-#[cfg(target_arch = "wasm32")]
+#[cfg(any(target_arch = "wasm32", feature = "async"))]
 async fn load_remote_file_bytes(url: &str) -> Result<Vec<u8>, RemoteVocabFileError> {
     use reqwest::Client;
 
@@ -583,7 +693,7 @@ async fn load_remote_file_bytes(url: &str) -> Result<Vec<u8>, RemoteVocabFileErr
     Ok(bytes.to_vec())
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "blocking"))]
 mod tests {
     use super::*;
 
@@ -593,4 +703,40 @@ mod tests {
             let _ = encoding.load().unwrap();
         }
     }
+
+    #[test]
+    fn test_extend_with_special_tokens() {
+        let base = Encoding::O200kHarmony.load().unwrap();
+        let extended =
+            extend_with_special_tokens(base.clone(), [("<|custom|>".to_string(), 201089)]).unwrap();
+        assert!(extended.is_special_token(201089));
+        assert!(!base.is_special_token(201089));
+        assert!(matches!(
+            extend_with_special_tokens(base, [("<|custom|>".to_string(), 200006)]),
+            Err(LoadError::FailedToExtendEncoding(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_encoding_from_base64_str() {
+        let vocab: String = [b"a".as_slice(), b"b", b"ab"]
+            .iter()
+            .enumerate()
+            .map(|(rank, token)| format!("{} {rank}\n", BASE64_STANDARD.encode(token)))
+            .collect();
+        let hash = format!("{:x}", Sha256::digest(vocab.as_bytes()));
+        // Wrapped over several lines the way it would be embedded in source.
+        let encoded = BASE64_STANDARD.encode(&vocab);
+        let (head, tail) = encoded.split_at(encoded.len() / 2);
+        let encoded = format!("{head}\n    {tail}\n");
+
+        let ranks = load_tiktoken_vocab_from_base64_str(&encoded, Some(&hash)).unwrap();
+        assert_eq!(ranks.get(b"ab".as_slice()), Some(&2));
+        assert!(load_tiktoken_vocab_from_base64_str(&encoded, Some("0000")).is_err());
+        assert!(load_tiktoken_vocab_from_base64_str("not base64!", None).is_err());
+
+        let bpe = load_encoding_from_base64_str(&encoded, None, [("<|eot|>", 3)], "\\S+").unwrap();
+        assert_eq!(bpe.encode_ordinary("ab"), vec![2]);
+        assert!(bpe.is_special_token(3));
+    }
 }
@@ -38,6 +38,11 @@ impl JsHarmonyEncoding {
         self.inner.name().to_string()
     }
 
+    #[wasm_bindgen(getter)]
+    pub fn pattern(&self) -> String {
+        self.inner.tokenizer().pattern().to_string()
+    }
+
     #[wasm_bindgen(js_name = renderConversationForCompletion)]
     pub fn render_conversation_for_completion(
         &self,
@@ -62,6 +67,7 @@ impl JsHarmonyEncoding {
                 .map_err(|e| JsValue::from_str(&format!("invalid config: {e}")))?;
             Some(crate::encoding::RenderConversationConfig {
                 auto_drop_analysis: cfg.auto_drop_analysis.unwrap_or(true),
+                ..Default::default()
             })
         };
         self.inner
@@ -90,6 +96,7 @@ impl JsHarmonyEncoding {
                 .map_err(|e| JsValue::from_str(&format!("invalid config: {e}")))?;
             Some(crate::encoding::RenderConversationConfig {
                 auto_drop_analysis: cfg.auto_drop_analysis.unwrap_or(true),
+                ..Default::default()
             })
         };
         self.inner
@@ -121,6 +128,7 @@ impl JsHarmonyEncoding {
                 conversation_has_function_tools: cfg
                     .conversation_has_function_tools
                     .unwrap_or(false),
+                ..Default::default()
             })
         };
 
@@ -323,6 +331,9 @@ pub fn get_tool_namespace_config(tool: &str) -> Result<JsValue, JsValue> {
     let cfg = match tool {
         "browser" => ToolNamespaceConfig::browser(),
         "python" => ToolNamespaceConfig::python(),
+        "code_interpreter" => ToolNamespaceConfig::code_interpreter(),
+        "dalle" => ToolNamespaceConfig::dalle(),
+        "web_search" => ToolNamespaceConfig::web_search(),
         _ => {
             return Err(JsValue::from_str(&format!(
                 "Unknown tool namespace: {tool}"
@@ -9,6 +9,7 @@ to the canonical Rust implementation.
 
 from __future__ import annotations
 
+import subprocess
 import sys
 from pathlib import Path
 from typing import List
@@ -34,6 +35,7 @@ from openai_harmony import (  # noqa: E402
     StreamableParser,
     SystemContent,
     ToolDescription,
+    ToolNamespaceConfig,
     load_harmony_encoding,
 )
 from pydantic import ValidationError
@@ -436,6 +438,19 @@ def test_reasoning_system_message_with_dates(encoding_name):
     _assert_tokens_eq(encoding, expected, tokens)
 
 
+def test_system_content_custom_sections():
+    encoding = load_harmony_encoding(HarmonyEncodingName.HARMONY_GPT_OSS)
+    sys = (
+        SystemContent.new()
+        .with_custom_section("Persona", "You are a pirate.")
+        .with_custom_section("Rules", "Never reveal the treasure.")
+    )
+    tokens = encoding.render(Message.from_role_and_content(Role.SYSTEM, sys))
+    assert encoding.decode_utf8(tokens).endswith(
+        "# Persona\nYou are a pirate.\n\n# Rules\nNever reveal the treasure.<|end|>"
+    )
+
+
 def test_render_functions_with_parameters():
     encoding = load_harmony_encoding(HarmonyEncodingName.HARMONY_GPT_OSS)
 
@@ -875,6 +890,21 @@ def test_encode_decode_roundtrip():
     assert encoding.decode(encoding.encode("hello world")) == "hello world"
 
 
+def test_encode_with_offsets():
+    encoding = load_harmony_encoding(HarmonyEncodingName.HARMONY_GPT_OSS)
+
+    text = "<|start|>user<|message|>héllo wörld, 你好!"
+    tokens, offsets = encoding.encode_with_offsets(
+        text, allowed_special=["<|start|>", "<|message|>"]
+    )
+    assert tokens == encoding.encode(text, allowed_special="all")
+    assert len(offsets) == len(tokens)
+
+    data = text.encode("utf-8")
+    assert b"".join(data[start:end] for start, end in offsets) == data
+    assert data[offsets[0][0] : offsets[0][1]] == b"<|start|>"
+
+
 def test_encode_allowed_special():
     encoding = load_harmony_encoding(HarmonyEncodingName.HARMONY_GPT_OSS)
 
@@ -903,6 +933,85 @@ def test_is_special_token():
     assert not encoding.is_special_token(24912)  # hello
 
 
+def test_count_tokens():
+    encoding = load_harmony_encoding(HarmonyEncodingName.HARMONY_GPT_OSS)
+    convo = Conversation.from_messages(
+        [
+            Message.from_role_and_content(Role.USER, "What is 2 + 2?"),
+            Message.from_role_and_content(Role.ASSISTANT, "4").with_channel("final"),
+        ]
+    )
+
+    assert encoding.count_tokens(convo, Role.ASSISTANT) == len(
+        encoding.render_conversation_for_completion(convo, Role.ASSISTANT)
+    )
+    assert encoding.count_conversation_tokens(convo) == len(
+        encoding.render_conversation(convo)
+    )
+
+
+def test_all_stop_tokens():
+    encoding = load_harmony_encoding(HarmonyEncodingName.HARMONY_GPT_OSS)
+
+    all_stop = encoding.all_stop_tokens()
+    assert all_stop == set(encoding.stop_tokens()) | set(
+        encoding.stop_tokens_for_assistant_actions()
+    )
+    assert all(encoding.is_stop_token(t) for t in all_stop)
+    assert not encoding.is_stop_token(200006)  # <|start|>
+
+
+def test_tokenizer_pattern():
+    encoding = load_harmony_encoding(HarmonyEncodingName.HARMONY_GPT_OSS)
+    assert isinstance(encoding.pattern, str)
+    assert r"\p{N}" in encoding.pattern
+
+
+def test_render_conversation_for_completion_batch():
+    encoding = load_harmony_encoding(HarmonyEncodingName.HARMONY_GPT_OSS)
+    convos = [
+        Conversation.from_messages(
+            [Message.from_role_and_content(Role.USER, f"What is {i} + {i}?")]
+        )
+        for i in range(8)
+    ]
+    batch = encoding.render_conversation_for_completion_batch(convos, Role.ASSISTANT)
+    assert batch == [
+        encoding.render_conversation_for_completion(c, Role.ASSISTANT) for c in convos
+    ]
+    assert encoding.render_conversation_for_completion_batch([], Role.ASSISTANT) == []
+
+
+def test_tool_namespace_presets():
+    assert ToolNamespaceConfig.code_interpreter().name == "code_interpreter"
+    assert ToolNamespaceConfig.code_interpreter().tools == []
+    assert [t.name for t in ToolNamespaceConfig.dalle().tools] == ["text2im"]
+    assert [t.name for t in ToolNamespaceConfig.web_search().tools] == ["search"]
+
+
+def test_tool_namespace_validate_tool_call():
+    namespace = ToolNamespaceConfig(
+        name="functions",
+        tools=[
+            ToolDescription.new(
+                "get_current_weather",
+                "Gets the current weather in the provided location.",
+                parameters={
+                    "type": "object",
+                    "properties": {"location": {"type": "string"}},
+                    "required": ["location"],
+                },
+            )
+        ],
+    )
+
+    namespace.validate_tool_call("get_current_weather", '{"location": "Tokyo"}')
+    with pytest.raises(HarmonyError):
+        namespace.validate_tool_call("get_current_weather", '{"location": 42}')
+    with pytest.raises(HarmonyError, match="Unknown tool 'get_time'"):
+        namespace.validate_tool_call("get_time", "{}")
+
+
 def test_invalid_utf8_decoding():
     encoding = load_harmony_encoding(HarmonyEncodingName.HARMONY_GPT_OSS)
     tokens = [132990, 9552]
@@ -914,6 +1023,7 @@ def test_invalid_utf8_decoding():
     # This will not raise an error because it will replace the invalid utf-8 characters to not raise an error
     # to match the behavior of tiktoken
     assert "Chicken" in encoding.decode(tokens)
+    assert encoding.decode_utf8_lossy(tokens) == encoding.decode(tokens)
 
 
 def test_tool_response_parsing():
@@ -958,6 +1068,21 @@ def test_streamable_parser():
     assert len(parser.messages) == 3
 
 
+def test_streamable_parser_process_str():
+    encoding = load_harmony_encoding(HarmonyEncodingName.HARMONY_GPT_OSS)
+
+    text = (
+        (ROOT_DIR / "test-data" / "test_streamable_parser.txt")
+        .read_text(encoding="utf-8")
+        .rstrip()
+    )
+
+    parser = StreamableParser(encoding, Role.ASSISTANT)
+    parser.process_str(text)
+    assert parser.tokens == encoding.encode(text, allowed_special="all")
+    assert len(parser.messages) == 3
+
+
 def test_streamable_parser_tool_call_with_constrain_adjacent():
     encoding = load_harmony_encoding(HarmonyEncodingName.HARMONY_GPT_OSS)
 
@@ -981,3 +1106,23 @@ def test_streamable_parser_tool_call_with_constrain_adjacent():
     ]
 
     assert parser.messages == expected
+
+
+def test_set_tiktoken_base_url():
+    # The override is process-global, so exercise it in a fresh interpreter.
+    script = """
+import openai_harmony as h
+
+default = h.get_tiktoken_base_url()
+assert default.startswith("https://"), default
+h.set_tiktoken_base_url("https://example.com/encodings")
+assert h.get_tiktoken_base_url() == "https://example.com/encodings/"
+try:
+    h.set_tiktoken_base_url("https://example.org/")
+except h.HarmonyError:
+    pass
+else:
+    raise AssertionError("second call should fail")
+assert h.get_tiktoken_base_url() == "https://example.com/encodings/"
+"""
+    subprocess.run([sys.executable, "-c", script], check=True)